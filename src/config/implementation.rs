@@ -1,3 +1,7 @@
+use std::collections::BTreeMap;
+
+use aws_sdk_dynamodb::types::AttributeValue;
+
 use super::interface::{Camera, CameraConfigRepository};
 pub struct AWSCameraConfigRepository {
     client: aws_sdk_dynamodb::Client,
@@ -59,10 +63,33 @@ impl CameraConfigRepository for AWSCameraConfigRepository {
             .filter_map(|item| {
                 let camera_id = item.get("sortKey")?.as_s().ok()?.to_string();
                 let source_url = item.get("url")?.as_s().ok()?.to_string();
+                // Optional: a map of strings and a string set or list.
+                let labels = item
+                    .get("labels")
+                    .and_then(|labels| labels.as_m().ok())
+                    .map(|labels| {
+                        labels
+                            .iter()
+                            .filter_map(|(key, value)| {
+                                Some((key.clone(), value.as_s().ok()?.clone()))
+                            })
+                            .collect::<BTreeMap<String, String>>()
+                    })
+                    .unwrap_or_default();
+                let tags = match item.get("tags") {
+                    Some(AttributeValue::Ss(tags)) => tags.clone(),
+                    Some(AttributeValue::L(tags)) => tags
+                        .iter()
+                        .filter_map(|tag| tag.as_s().ok().cloned())
+                        .collect(),
+                    _ => vec![],
+                };
 
                 Some(Camera {
                     id: camera_id,
                     source_url,
+                    labels,
+                    tags,
                 })
             })
             .collect();
@@ -1,9 +1,12 @@
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::env;
 #[derive(Debug, Clone)]
 pub struct Camera {
     pub id: String,
     pub source_url: String,
+    pub labels: BTreeMap<String, String>,
+    pub tags: Vec<String>,
 }
 
 pub trait CameraConfigRepository {
@@ -0,0 +1,83 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+const EVENT_BUFFER_SIZE: usize = 1024;
+
+/// What happened to a stream. The relay only forwards video and never writes
+/// it anywhere, so there is no recording event; one belongs here once streams
+/// can be recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamEventType {
+    StreamAdded,
+    StreamRemoved,
+    StreamExpired,
+    SourceConnected,
+    SourceDisconnected,
+    SourceSwitched,
+    ClientConnected,
+    ClientDisconnected,
+}
+
+impl StreamEventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StreamEventType::StreamAdded => "stream_added",
+            StreamEventType::StreamRemoved => "stream_removed",
+            StreamEventType::StreamExpired => "stream_expired",
+            StreamEventType::SourceConnected => "source_connected",
+            StreamEventType::SourceDisconnected => "source_disconnected",
+            StreamEventType::SourceSwitched => "source_switched",
+            StreamEventType::ClientConnected => "client_connected",
+            StreamEventType::ClientDisconnected => "client_disconnected",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StreamEvent {
+    #[serde(rename = "type")]
+    pub event_type: StreamEventType,
+    pub stream_id: String,
+    pub timestamp: String,
+    pub details: Option<serde_json::Value>,
+}
+
+/// Fan-out of stream lifecycle events. Publishing never blocks, so it is safe
+/// to call from GStreamer threads; subscribers that fall behind lose the
+/// oldest events.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<StreamEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_BUFFER_SIZE);
+        Self { sender }
+    }
+}
+
+impl EventBus {
+    pub fn publish(
+        &self,
+        event_type: StreamEventType,
+        stream_id: &str,
+        details: Option<serde_json::Value>,
+    ) {
+        let event = StreamEvent {
+            event_type,
+            stream_id: stream_id.to_owned(),
+            timestamp: Utc::now().to_rfc3339(),
+            details,
+        };
+        tracing::debug!("publishing event {:?}", event);
+        // An error only means nobody is subscribed right now.
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<StreamEvent> {
+        self.sender.subscribe()
+    }
+}
@@ -1,10 +1,17 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
 
 use chrono::Utc;
-use gst_rtsp_server::{RTSPMedia, RTSPMountPoints};
+use gst_rtsp_server::RTSPMountPoints;
 use serde::Serialize;
-use tokio::sync::Mutex;
-type MediaMap = Arc<Mutex<HashMap<String, Vec<glib::WeakRef<RTSPMedia>>>>>;
+
+use crate::{
+    events::EventBus,
+    metrics::RelayMetrics,
+    rtsp_server::{access::ClientAccessControl, clients::ClientTracker, codecs::Codecs, governor::TranscodeGovernor, lifecycle::StreamMode, masks::PrivacyMasks, overlay::Overlay, runtime::StreamRuntime, slate::SlateConfig, transform::VideoTransform},
+    webhooks::WebhookRegistry,
+};
+
+use super::{health::HealthState, registry::StreamRegistry, source_validation::SourceAllowlist};
 
 #[derive(Clone, Serialize)]
 pub struct StreamInfo {
@@ -23,42 +30,74 @@ pub struct StreamInfoInternal {
     pub id: String,
     pub name: String,
     pub url: String,
+    pub source_url: String,
+    pub labels: BTreeMap<String, String>,
+    pub tags: Vec<String>,
+    pub backup_source_urls: Vec<String>,
+    pub stall_timeout_seconds: Option<u64>,
+    pub slate: bool,
+    pub transform: VideoTransform,
+    pub max_framerate: Option<u32>,
+    pub keyframes_only: bool,
+    pub overlays: Vec<Overlay>,
+    pub masks: PrivacyMasks,
+    pub down_scale: bool,
+    pub allowed_client_ips: Vec<String>,
+    pub denied_client_ips: Vec<String>,
+    pub max_clients: Option<u32>,
+    pub mode: StreamMode,
+    pub linger_seconds: u64,
     pub expiration_date: ExpirationDate,
-    pub added_at: chrono::DateTime<Utc> 
+    pub added_at: chrono::DateTime<Utc>,
+    pub runtime: Arc<StreamRuntime>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub streams: Arc<Mutex<Vec<StreamInfoInternal>>>,
-    pub mounts: Arc<Mutex<RTSPMountPoints>>,
+    pub streams: Arc<StreamRegistry>,
+    pub clients: Arc<ClientTracker>,
     pub root_url: String,
     pub rtsp_root_url: String,
-    pub media_map: MediaMap,
     pub stream_expiration_time_in_minutes: i64,
-    pub stream_max_life_time_in_minutes: i64
+    pub stream_max_life_time_in_minutes: i64,
+    pub source_allowlist: Arc<SourceAllowlist>,
+    pub webhook_allowlist: Arc<SourceAllowlist>,
+    pub slate: SlateConfig,
+    pub overlay_image_dir: Option<PathBuf>,
+    pub codecs: Codecs,
+    pub governor: Arc<TranscodeGovernor>,
+    pub metrics: Arc<RelayMetrics>,
+    pub health: Arc<HealthState>,
+    pub events: EventBus,
+    pub webhooks: Arc<WebhookRegistry>,
+    /// Turns true once the relay shuts down, for responses that would
+    /// otherwise never end.
+    pub shutdown: tokio::sync::watch::Receiver<bool>,
 }
 
 impl AppState {
-    pub fn new(stream_expiration_time_in_minutes: i64, root_url: &str, rtsp_root_url: &str,  mounts: RTSPMountPoints, stream_max_life_time_in_minutes: i64) -> Self {
-        let streams: Vec<StreamInfoInternal> = vec![];
-        let streams = Mutex::new(streams);
-        let streams = Arc::new(streams);
-
-        let mounts: Mutex<RTSPMountPoints> = Mutex::new(mounts);
-        let mounts = Arc::new(mounts);
-
-        let media_map: HashMap<String, Vec<glib::WeakRef<RTSPMedia>>> = HashMap::new();
-        let media_map = Mutex::new(media_map);
-        let media_map = Arc::new(media_map);
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(stream_expiration_time_in_minutes: i64, root_url: &str, rtsp_root_url: &str,  mounts: RTSPMountPoints, stream_max_life_time_in_minutes: i64, source_allowlist: SourceAllowlist, webhook_allowlist: SourceAllowlist, slate: SlateConfig, overlay_image_dir: Option<PathBuf>, codecs: Codecs, governor: Arc<TranscodeGovernor>, access_control: Arc<ClientAccessControl>, clients: Arc<ClientTracker>, metrics: Arc<RelayMetrics>, health: Arc<HealthState>, events: EventBus, webhooks: Arc<WebhookRegistry>, shutdown: tokio::sync::watch::Receiver<bool>) -> Self {
+        let streams = Arc::new(StreamRegistry::new(mounts, access_control, clients.clone(), governor.clone()));
 
         AppState {
             streams,
-            mounts,
+            clients,
             root_url: root_url.to_owned(),
-            media_map,
             stream_expiration_time_in_minutes,
             rtsp_root_url: rtsp_root_url.to_owned(),
-            stream_max_life_time_in_minutes
+            stream_max_life_time_in_minutes,
+            source_allowlist: Arc::new(source_allowlist),
+            webhook_allowlist: Arc::new(webhook_allowlist),
+            slate,
+            overlay_image_dir,
+            codecs,
+            governor,
+            metrics,
+            health,
+            events,
+            webhooks,
+            shutdown,
         }
     }
 }
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{Path, Query, State},
+    http, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::rtsp_server::clients::ClientInfo;
+
+use super::{
+    appstate::AppState,
+    error::{AppError, UserInputError},
+};
+
+#[derive(Debug, Serialize)]
+pub struct StreamClientOutput {
+    pub client_id: String,
+    pub ip: Option<String>,
+    pub user: Option<String>,
+    pub started_at: String,
+    pub transport: Option<String>,
+}
+
+impl From<ClientInfo> for StreamClientOutput {
+    fn from(info: ClientInfo) -> Self {
+        Self {
+            client_id: info.client_id,
+            ip: info.ip,
+            user: info.user,
+            started_at: info.started_at.to_rfc3339(),
+            transport: info.transport,
+        }
+    }
+}
+
+pub(super) fn stream_not_found(id: &str) -> AppError {
+    AppError::UserInputError(UserInputError {
+        status_code: http::StatusCode::NOT_FOUND,
+        message: "stream not found".to_string(),
+        details: json!({ "id": id }),
+    })
+}
+
+pub async fn list_stream_clients(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<StreamClientOutput>>, AppError> {
+    if !state.streams.contains(&id) {
+        return Err(stream_not_found(&id));
+    }
+
+    let clients = state
+        .clients
+        .viewers(&id)
+        .into_iter()
+        .map(StreamClientOutput::from)
+        .collect();
+    Ok(Json(clients))
+}
+
+pub async fn disconnect_stream_client(
+    Path((id, client_id)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<Json<StreamClientOutput>, AppError> {
+    if !state.streams.contains(&id) {
+        return Err(stream_not_found(&id));
+    }
+
+    let client = state.clients.disconnect(&id, &client_id).ok_or_else(|| {
+        AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::NOT_FOUND,
+            message: "client not found".to_string(),
+            details: json!({ "id": id, "client_id": client_id }),
+        })
+    })?;
+    Ok(Json(StreamClientOutput::from(client)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DisconnectClientsQuery {
+    pub user: Option<String>,
+    pub stream_id: Option<String>,
+}
+
+/// Disconnects the viewers authenticated as `?user=`, limited to one stream
+/// with `?stream_id=`. Viewers sharing the relay's RTSP account can not be
+/// told apart, so disconnecting that account needs a `stream_id`; either way
+/// they can connect again right away.
+pub async fn disconnect_clients(
+    Query(query): Query<DisconnectClientsQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<Vec<StreamClientOutput>>, AppError> {
+    let Some(user) = query.user.filter(|user| !user.is_empty()) else {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "user is required".to_string(),
+            details: json!({ "field": "user" }),
+        }));
+    };
+
+    let stream_id = query.stream_id.filter(|stream_id| !stream_id.is_empty());
+    if stream_id.is_none() && state.clients.is_shared_user(&user) {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "every viewer shares this user, pick a stream_id to disconnect them from"
+                .to_string(),
+            details: json!({ "field": "stream_id", "user": user }),
+        }));
+    }
+    if let Some(stream_id) = &stream_id {
+        if !state.streams.contains(stream_id) {
+            return Err(stream_not_found(stream_id));
+        }
+    }
+
+    let clients = state
+        .clients
+        .disconnect_user(&user, stream_id.as_deref())
+        .into_iter()
+        .map(StreamClientOutput::from)
+        .collect();
+    Ok(Json(clients))
+}
@@ -1,24 +1,56 @@
-use crate::http_server::{
-    appstate::ExpirationDate,
-    error::{InternalError, UserInputError},
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+
+use crate::{
+    events::StreamEventType,
+    http_server::{
+        appstate::ExpirationDate,
+        error::{InternalError, UserInputError},
+        source_validation::validate_source_url,
+    },
+    net::cidr::CidrBlock,
+    rtsp_server::{
+        access::ClientIpPolicy,
+        factory::{PipelineSpec, RelayMediaFactory},
+        governor::TranscodeRejection,
+        lifecycle::{MediaLifecycle, StreamMode},
+        masks::{PrivacyMask, PrivacyMasks},
+        overlay::Overlay,
+        runtime::StreamRuntime,
+        transform::VideoTransform,
+    },
 };
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http,
     response::IntoResponse,
     Json,
 };
 use chrono::Utc;
-use gst_rtsp_server::prelude::{RTSPMediaExt, RTSPMediaFactoryExt, RTSPMountPointsExt};
+use gst_rtsp_server::{
+    prelude::{RTSPMediaExt, RTSPMediaFactoryExt},
+    RTSPMedia, RTSPSuspendMode,
+};
+use gstreamer::prelude::ElementExt;
 use serde::{Deserialize, Serialize};
-use tokio::task;
+use serde_json::json;
 use tracing;
 use ulid::Ulid;
 
 use super::{
     appstate::{AppState, StreamInfo, StreamInfoInternal},
     error::AppError,
+    labels::validate_labels,
+    listing::StreamListQuery,
+    registry::{StreamAddError, StreamEntry, StreamMedias},
 };
 
+const DEFAULT_STALL_TIMEOUT_SECONDS: u64 = 5;
+/// Longest stall timeout accepted, sources are restarted after three of them.
+const MAX_STALL_TIMEOUT_SECONDS: u64 = 3600;
+/// Highest `max_framerate` accepted, in frames per second.
+const MAX_FRAMERATE: u32 = 120;
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct AddStreamOutput {
     id: String,
@@ -31,69 +63,292 @@ pub struct AddStreamOutput {
 pub struct AddStreamInput {
     pub name: String,
     pub source_url: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub backup_source_urls: Vec<String>,
+    #[serde(default)]
+    pub stall_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub slate: bool,
+    #[serde(default)]
+    pub transform: VideoTransform,
+    #[serde(default)]
+    pub max_framerate: Option<u32>,
+    #[serde(default)]
+    pub keyframes_only: bool,
+    #[serde(default)]
+    pub overlays: Vec<Overlay>,
+    #[serde(default)]
+    pub masks: Vec<PrivacyMask>,
     pub down_scale: bool,
     pub expirable: bool,
+    #[serde(default)]
+    pub allowed_client_ips: Vec<String>,
+    #[serde(default)]
+    pub denied_client_ips: Vec<String>,
+    #[serde(default)]
+    pub max_clients: Option<u32>,
+    #[serde(default)]
+    pub mode: StreamMode,
+    #[serde(default)]
+    pub linger_seconds: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct AddStreamToStateInput {
     pub id: String,
     pub name: String,
     pub source_url: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub backup_source_urls: Vec<String>,
+    #[serde(default)]
+    pub stall_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub slate: bool,
+    #[serde(default)]
+    pub transform: VideoTransform,
+    #[serde(default)]
+    pub max_framerate: Option<u32>,
+    #[serde(default)]
+    pub keyframes_only: bool,
+    #[serde(default)]
+    pub overlays: Vec<Overlay>,
+    #[serde(default)]
+    pub masks: Vec<PrivacyMask>,
     pub down_scale: bool,
     pub expirable: bool,
+    /// When an expirable stream expires. `None` starts the expiration time
+    /// from now.
+    #[serde(default)]
+    pub expires_at: Option<chrono::DateTime<Utc>>,
+    #[serde(default)]
+    pub allowed_client_ips: Vec<String>,
+    #[serde(default)]
+    pub denied_client_ips: Vec<String>,
+    #[serde(default)]
+    pub max_clients: Option<u32>,
+    #[serde(default)]
+    pub mode: StreamMode,
+    #[serde(default)]
+    pub linger_seconds: u64,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AddPermanentStreamInput {
     pub name: String,
     pub source_url: String,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub backup_source_urls: Vec<String>,
+    #[serde(default)]
+    pub stall_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    pub slate: bool,
+    #[serde(default)]
+    pub transform: VideoTransform,
+    #[serde(default)]
+    pub max_framerate: Option<u32>,
+    #[serde(default)]
+    pub keyframes_only: bool,
+    #[serde(default)]
+    pub overlays: Vec<Overlay>,
+    #[serde(default)]
+    pub masks: Vec<PrivacyMask>,
     pub down_scale: bool,
+    #[serde(default)]
+    pub allowed_client_ips: Vec<String>,
+    #[serde(default)]
+    pub denied_client_ips: Vec<String>,
+    #[serde(default)]
+    pub max_clients: Option<u32>,
+    #[serde(default)]
+    pub mode: StreamMode,
+    #[serde(default)]
+    pub linger_seconds: u64,
 }
 
-pub async fn add_stream_to_state(
-    state: AppState,
-    req: AddStreamToStateInput,
-) -> Result<AddStreamOutput, AppError> {
-    let media_map_clone = state.media_map.clone();
-    let handle = tokio::runtime::Handle::current();
-    let factory = gst_rtsp_server::RTSPMediaFactory::new();
+fn parse_client_ip_list(field: &str, values: &[String]) -> Result<Vec<CidrBlock>, AppError> {
+    values
+        .iter()
+        .map(|value| {
+            CidrBlock::parse(value).map_err(|err| {
+                AppError::UserInputError(UserInputError {
+                    status_code: http::StatusCode::BAD_REQUEST,
+                    message: format!("{} contains an invalid address or network", field),
+                    details: json!({
+                        "field": field,
+                        "value": value,
+                        "reason": err.reason,
+                    }),
+                })
+            })
+        })
+        .collect()
+}
 
-    let source_url = req.source_url.clone();
+/// A stream built from validated input that is not mounted yet.
+struct NewStream {
+    info: StreamInfoInternal,
+    factory: RelayMediaFactory,
+    medias: StreamMedias,
+    lifecycle: Arc<MediaLifecycle>,
+    client_ip_policy: ClientIpPolicy,
+    output: AddStreamOutput,
+}
 
-    let launch = if req.down_scale {
-        format!(
-            "rtspsrc location={} latency=0 ! rtph264depay ! h264parse ! avdec_h264 ! videoscale ! video/x-raw,width=640,height=320,format=I420 ! x264enc tune=zerolatency bitrate=500 speed-preset=ultrafast key-int-max=30 ! h264parse ! rtph264pay config-interval=1 name=pay0 pt=96",
-            source_url
-        )
-    } else {
-        format!(
-            "rtspsrc location={} latency=50 protocols=tcp ! \
-             rtph264depay ! h264parse config-interval=1 ! \
-             rtph264pay name=pay0 pt=96",
-            source_url
-        )
+/// Validates `req` and builds the stream it describes, leaving the streams
+/// that are mounted alone.
+fn build_stream(state: &AppState, req: AddStreamToStateInput) -> Result<NewStream, AppError> {
+    let client_ip_policy = ClientIpPolicy {
+        allow: parse_client_ip_list("allowed_client_ips", &req.allowed_client_ips)?,
+        deny: parse_client_ip_list("denied_client_ips", &req.denied_client_ips)?,
     };
-
-    factory.set_launch(&launch);
+    if req
+        .stall_timeout_seconds
+        .is_some_and(|seconds| seconds == 0 || seconds > MAX_STALL_TIMEOUT_SECONDS)
+    {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: format!(
+                "stall_timeout_seconds must be between 1 and {}",
+                MAX_STALL_TIMEOUT_SECONDS
+            ),
+            details: json!({ "field": "stall_timeout_seconds" }),
+        }));
+    }
+    if !req.transform.is_identity() && !req.down_scale {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "transforms can only be applied to transcoded streams, set down_scale"
+                .to_string(),
+            details: json!({ "field": "transform" }),
+        }));
+    }
+    if let Err(err) = req.transform.validate() {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: err.reason,
+            details: json!({ "field": "transform.crop" }),
+        }));
+    }
+    if req
+        .max_framerate
+        .is_some_and(|framerate| framerate == 0 || framerate > MAX_FRAMERATE)
+    {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: format!("max_framerate must be between 1 and {}", MAX_FRAMERATE),
+            details: json!({ "field": "max_framerate" }),
+        }));
+    }
+    if req.max_framerate.is_some() && !req.down_scale {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "max_framerate can only be applied to transcoded streams, use keyframes_only \
+                      on passthrough streams"
+                .to_string(),
+            details: json!({ "field": "max_framerate" }),
+        }));
+    }
+    if req.keyframes_only && req.down_scale {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "keyframes_only is for passthrough streams, use max_framerate on transcoded \
+                      streams"
+                .to_string(),
+            details: json!({ "field": "keyframes_only" }),
+        }));
+    }
+    if !req.overlays.is_empty() && !req.down_scale {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "overlays can only be drawn on transcoded streams, set down_scale".to_string(),
+            details: json!({ "field": "overlays" }),
+        }));
+    }
+    let overlays = req
+        .overlays
+        .iter()
+        .enumerate()
+        .map(|(index, overlay)| {
+            overlay
+                .resolve(&req.name, state.overlay_image_dir.as_deref())
+                .map_err(|err| {
+                    AppError::UserInputError(UserInputError {
+                        status_code: http::StatusCode::BAD_REQUEST,
+                        message: err.reason,
+                        details: json!({ "field": format!("overlays[{}]", index) }),
+                    })
+                })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    validate_masks(&req.masks, req.down_scale)?;
+    let tags = validate_labels(&req.labels, &req.tags)?;
+    if req.max_clients == Some(0) {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "max_clients must be greater than zero".to_string(),
+            details: json!({ "field": "max_clients" }),
+        }));
+    }
+    let id = req.id;
+    let runtime = Arc::new(StreamRuntime::new(
+        &id,
+        &state.metrics,
+        state.events.clone(),
+    ));
+    let masks = PrivacyMasks::new(req.masks.clone());
+    let factory = RelayMediaFactory::new(
+        PipelineSpec {
+            source_url: req.source_url.clone(),
+            backup_source_urls: req.backup_source_urls.clone(),
+            stall_timeout: Duration::from_secs(
+                req.stall_timeout_seconds
+                    .unwrap_or(DEFAULT_STALL_TIMEOUT_SECONDS),
+            ),
+            slate: req.slate.then(|| state.slate.clone()),
+            down_scale: req.down_scale,
+            codecs: state.codecs,
+            governor: state.governor.clone(),
+            transform: req.transform,
+            max_framerate: req.max_framerate,
+            keyframes_only: req.keyframes_only,
+            overlays,
+            masks: masks.clone(),
+        },
+        runtime.clone(),
+    );
 
     factory.set_shared(true);
-    let id = req.id;
-    let path = format!("/{}", id.to_string());
-    let path_clone = path.clone();
+    if req.mode == StreamMode::AlwaysOn {
+        // Keep the pipeline running between viewers instead of pausing it.
+        factory.set_suspend_mode(RTSPSuspendMode::None);
+    }
+    let lifecycle = Arc::new(MediaLifecycle::new(
+        &id,
+        req.mode,
+        Duration::from_secs(req.linger_seconds),
+        &factory,
+    ));
+    let medias = StreamMedias::default();
+    let configured_medias = medias.clone();
+    let configured_lifecycle = lifecycle.clone();
     factory.connect_media_configure(move |_, media| {
-        let mut media_map = task::block_in_place(|| handle.block_on(media_map_clone.lock()));
-
-        let v = media_map.entry(path_clone.clone()).or_insert_with(Vec::new);
-        v.push(glib::object::ObjectExt::downgrade(&media));
+        configured_medias.register(media);
+        configured_lifecycle.media_configured(media);
     });
 
     let url = format!("{}{}", state.rtsp_root_url, id.to_string());
-    state
-        .mounts
-        .lock()
-        .await
-        .add_factory(&path.to_string(), factory);
     let stream_info = StreamInfo {
         id: id.to_string(),
         name: req.name.clone(),
@@ -101,9 +356,9 @@ pub async fn add_stream_to_state(
     };
 
     let expiration_date = if req.expirable {
-        let current_time =
-            chrono::Utc::now() + chrono::Duration::minutes(state.stream_expiration_time_in_minutes);
-        ExpirationDate::At(current_time)
+        ExpirationDate::At(req.expires_at.unwrap_or_else(|| {
+            chrono::Utc::now() + chrono::Duration::minutes(state.stream_expiration_time_in_minutes)
+        }))
     } else {
         ExpirationDate::Never
     };
@@ -121,24 +376,184 @@ pub async fn add_stream_to_state(
         url: stream_info.url,
         id: stream_info.id,
         name: stream_info.name,
+        source_url: req.source_url,
+        labels: req.labels,
+        tags,
+        backup_source_urls: req.backup_source_urls,
+        stall_timeout_seconds: req.stall_timeout_seconds,
+        slate: req.slate,
+        transform: req.transform,
+        max_framerate: req.max_framerate,
+        keyframes_only: req.keyframes_only,
+        overlays: req.overlays,
+        masks,
+        down_scale: req.down_scale,
+        allowed_client_ips: req.allowed_client_ips,
+        denied_client_ips: req.denied_client_ips,
+        max_clients: req.max_clients,
+        mode: req.mode,
+        linger_seconds: req.linger_seconds,
         added_at: chrono::Utc::now(),
         expiration_date,
+        runtime,
     };
-    state.streams.lock().await.push(stream_info_internal);
+
+    Ok(NewStream {
+        info: stream_info_internal,
+        factory,
+        medias,
+        lifecycle,
+        client_ip_policy,
+        output,
+    })
+}
+
+fn stream_add_error(err: StreamAddError) -> AppError {
+    match err {
+        StreamAddError::AlreadyExists { id } => AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::CONFLICT,
+            message: "stream already exists".to_string(),
+            details: json!({ "id": id }),
+        }),
+        StreamAddError::Transcoding(TranscodeRejection::TooManyTranscodes {
+            transcodes,
+            max_transcodes,
+        }) => AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::TOO_MANY_REQUESTS,
+            message: "the relay already transcodes as many streams as it may, remove one or \
+                      add this stream without down_scale"
+                .to_string(),
+            details: json!({
+                "field": "down_scale",
+                "transcodes": transcodes,
+                "max_transcodes": max_transcodes,
+            }),
+        }),
+        StreamAddError::Transcoding(TranscodeRejection::Overloaded {
+            cpu_percent,
+            max_cpu_percent,
+        }) => AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::SERVICE_UNAVAILABLE,
+            message: "the relay is too busy to transcode another stream, try again later"
+                .to_string(),
+            details: json!({
+                "field": "down_scale",
+                "cpu_percent": cpu_percent.round(),
+                "max_cpu_percent": max_cpu_percent,
+            }),
+        }),
+    }
+}
+
+pub async fn add_stream_to_state(
+    state: AppState,
+    req: AddStreamToStateInput,
+) -> Result<AddStreamOutput, AppError> {
+    let stream = build_stream(&state, req)?;
+    state
+        .streams
+        .add(
+            stream.info,
+            stream.factory,
+            stream.medias,
+            stream.lifecycle,
+            stream.client_ip_policy,
+        )
+        .map_err(stream_add_error)?;
+    let output = stream.output;
+    state.events.publish(
+        StreamEventType::StreamAdded,
+        &output.id,
+        Some(json!({ "name": output.name, "url": output.url })),
+    );
 
     Ok(output)
 }
 
+/// Checks privacy masks before they are stored on a stream.
+pub fn validate_masks(masks: &[PrivacyMask], down_scale: bool) -> Result<(), AppError> {
+    if !masks.is_empty() && !down_scale {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::BAD_REQUEST,
+            message: "privacy masks can only be applied to transcoded streams, set down_scale"
+                .to_string(),
+            details: json!({ "field": "masks" }),
+        }));
+    }
+    for (index, mask) in masks.iter().enumerate() {
+        mask.validate().map_err(|err| {
+            AppError::UserInputError(UserInputError {
+                status_code: http::StatusCode::BAD_REQUEST,
+                message: err.reason,
+                details: json!({ "field": format!("masks[{}]", index) }),
+            })
+        })?;
+    }
+
+    Ok(())
+}
+
+pub async fn validate_backup_source_urls(
+    urls: &[String],
+    state: &AppState,
+) -> Result<Vec<String>, AppError> {
+    let mut validated = Vec::with_capacity(urls.len());
+    for (index, url) in urls.iter().enumerate() {
+        let url = validate_source_url(url, &state.source_allowlist)
+            .await
+            .map_err(|err| match err {
+                AppError::UserInputError(mut err) => {
+                    if let Some(details) = err.details.as_object_mut() {
+                        details.insert(
+                            "field".to_string(),
+                            json!(format!("backup_source_urls[{}]", index)),
+                        );
+                    }
+                    AppError::UserInputError(err)
+                }
+                err => err,
+            })?;
+        validated.push(url.to_string());
+    }
+
+    Ok(validated)
+}
+
 pub async fn add_stream(
     State(state): State<AppState>,
     Json(req): Json<AddStreamInput>,
 ) -> impl IntoResponse {
+    let source_url = match validate_source_url(&req.source_url, &state.source_allowlist).await {
+        Ok(source_url) => source_url,
+        Err(err) => return Err(err.into_response()),
+    };
+    let backup_source_urls =
+        match validate_backup_source_urls(&req.backup_source_urls, &state).await {
+            Ok(backup_source_urls) => backup_source_urls,
+            Err(err) => return Err(err.into_response()),
+        };
     let add_stream_internal_input = AddStreamToStateInput {
         id: Ulid::new().to_string(),
         name: req.name,
-        source_url: req.source_url,
+        source_url: source_url.to_string(),
+        labels: req.labels,
+        tags: req.tags,
+        backup_source_urls,
+        stall_timeout_seconds: req.stall_timeout_seconds,
+        slate: req.slate,
+        transform: req.transform,
+        max_framerate: req.max_framerate,
+        keyframes_only: req.keyframes_only,
+        overlays: req.overlays,
+        masks: req.masks,
         down_scale: req.down_scale,
         expirable: req.expirable,
+        expires_at: None,
+        allowed_client_ips: req.allowed_client_ips,
+        denied_client_ips: req.denied_client_ips,
+        max_clients: req.max_clients,
+        mode: req.mode,
+        linger_seconds: req.linger_seconds,
     };
     match add_stream_to_state(state, add_stream_internal_input).await {
         Ok(output) => Ok(Json(output)),
@@ -151,126 +566,144 @@ pub async fn put_permanent_stream(
     Path(id): Path<String>,
     Json(req): Json<AddPermanentStreamInput>,
 ) -> Result<Json<AddStreamOutput>, AppError> {
+    let source_url = validate_source_url(&req.source_url, &state.source_allowlist).await?;
+    let backup_source_urls = validate_backup_source_urls(&req.backup_source_urls, &state).await?;
     let add_stream_internal_input = AddStreamToStateInput {
         id: id.clone(),
         name: req.name,
-        source_url: req.source_url,
+        source_url: source_url.to_string(),
+        labels: req.labels,
+        tags: req.tags,
+        backup_source_urls,
+        stall_timeout_seconds: req.stall_timeout_seconds,
+        slate: req.slate,
+        transform: req.transform,
+        max_framerate: req.max_framerate,
+        keyframes_only: req.keyframes_only,
+        overlays: req.overlays,
+        masks: req.masks,
         down_scale: req.down_scale,
         expirable: false,
+        expires_at: None,
+        allowed_client_ips: req.allowed_client_ips,
+        denied_client_ips: req.denied_client_ips,
+        max_clients: req.max_clients,
+        mode: req.mode,
+        linger_seconds: req.linger_seconds,
     };
-    remove_stream_by_id(&add_stream_internal_input.id, &state).await?;
-    let result = add_stream_to_state(state, add_stream_internal_input).await?;
-    Ok(Json(result))
+    // The new stream is built and admitted before the old one is touched, so
+    // a rejected request leaves the running stream alone.
+    let stream = build_stream(&state, add_stream_internal_input)?;
+    let (_, replaced) = state
+        .streams
+        .replace(
+            stream.info,
+            stream.factory,
+            stream.medias,
+            stream.lifecycle,
+            stream.client_ip_policy,
+        )
+        .map_err(stream_add_error)?;
+    if let Some(replaced) = replaced {
+        tracing::info!("replaced factory {}", replaced.path);
+        state.metrics.remove_stream(&id);
+        state
+            .events
+            .publish(StreamEventType::StreamRemoved, &id, None);
+        unprepare_medias(&replaced)?;
+    }
+    let output = stream.output;
+    state.events.publish(
+        StreamEventType::StreamAdded,
+        &output.id,
+        Some(json!({ "name": output.name, "url": output.url })),
+    );
+    Ok(Json(output))
 }
 
-async fn remove_stream_if_has_no_clients(id: &str, state: &AppState) -> Result<(), AppError> {
-    let medias = state.media_map.lock().await;
-    let mut streams_infos = state.streams.lock().await;
-
-    let path = format!("/{}", id.to_string());
-    let medias = medias.get(&path);
-    if let Some(medias) = medias {
-        let clients_count = medias.len();
-        let found_clients = if clients_count > 0 {
-            let mut found_clients_count = 0;
-            for weak_media in medias {
-                if let Some(media) = weak_media.upgrade() {
-                    found_clients_count += media.n_streams()
-                }
-
-                if found_clients_count > 0 {
-                    break;
-                }
-            }
+fn unprepare_medias(entry: &StreamEntry) -> Result<(), AppError> {
+    entry.lifecycle.stop();
+    let medias = entry.medias.medias();
+    tracing::info!("{} medias found", medias.len());
+    for media in medias {
+        media.unprepare().map_err(|err| {
+            AppError::InternalError(InternalError {
+                debug_message: format!("error while unpreparing media: {:?}", err),
+            })
+        })?;
+    }
 
-            found_clients_count
-        } else {
-            0
-        };
+    Ok(())
+}
 
-        let stream = streams_infos.iter().find(|s| s.id == id);
-        let now = Utc::now();
-        if stream.is_none() {
-            tracing::warn!("stream not found while trying to remove it");
-            return Ok(());
+fn remove_stream_if_has_no_clients(id: &str, state: &AppState) -> Result<(), AppError> {
+    let now = Utc::now();
+    let removed = state.streams.remove_if(id, |entry| {
+        if !entry.medias.was_configured() {
+            return false;
         }
-        let stream = stream.unwrap();
-        let minutes_until_expiration = (now - stream.added_at).num_minutes();
+        let found_clients = state.clients.viewer_count(&entry.id);
+        let minutes_until_expiration = (now - entry.info().added_at).num_minutes();
         if found_clients > 0 && minutes_until_expiration < state.stream_max_life_time_in_minutes {
             tracing::info!("{} clients found, ignoring", found_clients);
-            return Ok(());
-        } else {
-            tracing::info!("removing factory {}", path);
-            state.mounts.lock().await.remove_factory(&path);
-            streams_infos.retain(|e| e.id != id);
-
-            tracing::info!("{} clients found", medias.len());
-            for weak_media in medias {
-                if let Some(media) = weak_media.upgrade() {
-                    media.unprepare().map_err(|err| {
-                        AppError::InternalError(InternalError {
-                            debug_message: format!("error while unpreparing media: {:?}", err),
-                        })
-                    })?;
-                }
-            }
+            return false;
         }
-    }
+        true
+    });
+    let Some(entry) = removed else {
+        return Ok(());
+    };
 
-    Ok(())
+    tracing::info!("removed factory {}", entry.path);
+    state.metrics.remove_stream(id);
+    state.metrics.stale_stream_removals.inc();
+    state
+        .events
+        .publish(StreamEventType::StreamExpired, id, None);
+
+    unprepare_medias(&entry)
 }
 
-async fn remove_stream_by_id(id: &str, state: &AppState) -> Result<(), AppError> {
-    let mut streams_infos = state.streams.lock().await;
-    streams_infos.retain(|e| e.id != id);
-    let path = format!("/{}", id.to_string());
-    tracing::info!("removing factory {}", path);
-    state.mounts.lock().await.remove_factory(&path);
-    let medias = state.media_map.lock().await;
-    let medias = medias.get(&path);
-    if let Some(medias) = medias {
-        tracing::info!("{} clients found", medias.len());
-        for weak_media in medias {
-            if let Some(media) = weak_media.upgrade() {
-                media.unprepare().map_err(|err| {
-                    AppError::InternalError(InternalError {
-                        debug_message: format!("error while unpreparing media: {:?}", err),
-                    })
-                })?;
-            }
-        }
-    }
+fn remove_stream_by_id(id: &str, state: &AppState) -> Result<(), AppError> {
+    state.metrics.remove_stream(id);
+    let Some(entry) = state.streams.remove(id) else {
+        return Ok(());
+    };
 
-    Ok(())
+    tracing::info!("removed factory {}", entry.path);
+    state
+        .events
+        .publish(StreamEventType::StreamRemoved, id, None);
+
+    unprepare_medias(&entry)
 }
 
 pub async fn remove_stream(
     Path(id): Path<String>,
     State(state): State<AppState>,
 ) -> Result<String, AppError> {
-    remove_stream_by_id(&id, &state).await?;
+    remove_stream_by_id(&id, &state)?;
     Ok("Stream Removed".to_string())
 }
 
 pub async fn remove_stale_streams(state: State<AppState>) -> Result<String, AppError> {
     let current_time = chrono::Utc::now();
-    let stale_streams_ids = {
-        let streams = state.streams.lock().await;
-
-        streams
-            .iter()
-            .filter(|s| match s.expiration_date {
-                ExpirationDate::Never => true,
-                ExpirationDate::At(expiration_date) => expiration_date <= current_time,
-            })
-            .map(|s| s.id.clone())
-            .collect::<Vec<String>>()
-    };
+    let stale_streams_ids = state
+        .streams
+        .list()
+        .iter()
+        .filter(|entry| match entry.info().expiration_date {
+            // Always-on streams are meant to stay up without viewers.
+            ExpirationDate::Never => entry.lifecycle.mode != StreamMode::AlwaysOn,
+            ExpirationDate::At(expiration_date) => expiration_date <= current_time,
+        })
+        .map(|entry| entry.id.clone())
+        .collect::<Vec<String>>();
 
     tracing::info!("{} stale streams found", stale_streams_ids.len());
 
     for stale_stream in &stale_streams_ids {
-        if let Err(err) = remove_stream_if_has_no_clients(stale_stream, &state).await {
+        if let Err(err) = remove_stream_if_has_no_clients(stale_stream, &state) {
             let reason = match err {
                 AppError::UserInputError(user_input_error) => user_input_error.message,
                 AppError::InternalError(internal_error) => internal_error.debug_message,
@@ -282,33 +715,186 @@ pub async fn remove_stale_streams(state: State<AppState>) -> Result<String, AppE
     Ok("Stale streams removed".to_owned())
 }
 
+#[derive(Debug, Serialize)]
+pub struct StreamStatus {
+    pub pipeline_state: String,
+    pub upstream_connected: bool,
+    pub active_source: usize,
+    pub slate_active: bool,
+    pub stalled: bool,
+    pub frozen: bool,
+    pub timestamp_discontinuities: u64,
+    pub last_discontinuity_at: Option<String>,
+    pub viewers: u32,
+    pub codec: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub framerate: Option<String>,
+    pub bitrate_bps: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct StreamInfoListItem {
     pub id: String,
     pub name: String,
     pub url: String,
+    pub labels: BTreeMap<String, String>,
+    pub tags: Vec<String>,
     pub added_at: String,
     pub expiration_date: Option<String>,
+    pub max_clients: Option<u32>,
+    pub mode: StreamMode,
+    pub linger_seconds: u64,
+    pub slate: bool,
+    pub transform: VideoTransform,
+    pub max_framerate: Option<u32>,
+    pub keyframes_only: bool,
+    pub overlays: Vec<Overlay>,
+    pub masks: Vec<PrivacyMask>,
+    pub status: StreamStatus,
 }
+
+fn pipeline_state(medias: &[RTSPMedia]) -> &'static str {
+    medias
+        .iter()
+        .map(|media| match media.element().current_state() {
+            gstreamer::State::Playing => (3, "PLAYING"),
+            gstreamer::State::Paused => (2, "PAUSED"),
+            gstreamer::State::Ready => (1, "READY"),
+            _ => (0, "NULL"),
+        })
+        .max_by_key(|(rank, _)| *rank)
+        .map(|(_, name)| name)
+        .unwrap_or("NULL")
+}
+
+fn stream_list_item(entry: &StreamEntry, viewers: usize) -> StreamInfoListItem {
+    let stream = entry.info();
+    let medias = entry.medias.medias();
+    let runtime = stream.runtime.snapshot();
+
+    StreamInfoListItem {
+        id: stream.id.clone(),
+        name: stream.name.clone(),
+        url: stream.url.clone(),
+        labels: stream.labels.clone(),
+        tags: stream.tags.clone(),
+        added_at: stream.added_at.to_rfc3339(),
+        expiration_date: match stream.expiration_date {
+            ExpirationDate::Never => None,
+            ExpirationDate::At(date_time) => Some(date_time.to_rfc3339()),
+        },
+        max_clients: stream.max_clients,
+        mode: stream.mode,
+        linger_seconds: stream.linger_seconds,
+        slate: stream.slate,
+        transform: stream.transform,
+        max_framerate: stream.max_framerate,
+        keyframes_only: stream.keyframes_only,
+        overlays: stream.overlays.clone(),
+        masks: stream.masks.get(),
+        status: StreamStatus {
+            pipeline_state: pipeline_state(&medias).to_owned(),
+            upstream_connected: runtime.upstream_connected,
+            active_source: runtime.active_source,
+            slate_active: runtime.slate_active,
+            stalled: runtime.stalled,
+            frozen: runtime.frozen,
+            timestamp_discontinuities: runtime.timestamp_discontinuities,
+            last_discontinuity_at: runtime
+                .last_discontinuity_at
+                .map(|date_time| date_time.to_rfc3339()),
+            viewers: viewers as u32,
+            codec: runtime.media_info.codec,
+            width: runtime.media_info.width,
+            height: runtime.media_info.height,
+            framerate: runtime.media_info.framerate,
+            bitrate_bps: runtime.bitrate_bps,
+            last_error: runtime.last_error,
+            last_error_at: runtime
+                .last_error_at
+                .map(|date_time| date_time.to_rfc3339()),
+        },
+    }
+}
+
+/// Lists the streams a page at a time. The cursor of the next page, if there
+/// is one, is returned in the `X-Next-Cursor` header.
 pub async fn list_streams(
     state: State<AppState>,
-) -> Result<Json<Vec<StreamInfoListItem>>, AppError> {
-    let mut result: Vec<StreamInfoListItem> = vec![];
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<impl IntoResponse, AppError> {
+    let query = StreamListQuery::parse(params)?;
+    let (page, next_cursor) = query.page(state.streams.list());
+    let result = page
+        .iter()
+        .map(|entry| stream_list_item(entry, state.clients.viewer_count(&entry.id)))
+        .collect::<Vec<StreamInfoListItem>>();
+
+    let mut headers = http::HeaderMap::new();
+    if let Some(next_cursor) =
+        next_cursor.and_then(|cursor| cursor.parse::<http::HeaderValue>().ok())
     {
-        let streams = state.streams.lock().await;
-        for stream in streams.iter() {
-            result.push(StreamInfoListItem {
-                id: stream.id.clone(),
-                name: stream.name.clone(),
-                url: stream.url.clone(),
-                added_at: stream.added_at.to_rfc3339(),
-                expiration_date: match stream.expiration_date {
-                    ExpirationDate::Never => None,
-                    ExpirationDate::At(date_time) => Some(date_time.to_rfc3339()),
-                },
-            });
-        }
+        headers.insert(NEXT_CURSOR_HEADER, next_cursor);
+    }
+    Ok((headers, Json(result)))
+}
+
+pub async fn get_stream(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<StreamInfoListItem>, AppError> {
+    let entry = state.streams.get(&id).ok_or_else(|| {
+        AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::NOT_FOUND,
+            message: "stream not found".to_string(),
+            details: json!({ "id": id }),
+        })
+    })?;
+
+    Ok(Json(stream_list_item(
+        &entry,
+        state.clients.viewer_count(&id),
+    )))
+}
+
+pub async fn export_metrics(state: State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let streams = state.streams.list();
+    let expirable = streams
+        .iter()
+        .filter(|entry| matches!(entry.info().expiration_date, ExpirationDate::At(_)))
+        .count();
+    let metrics = &state.metrics.streams;
+    metrics
+        .with_label_values(&["expirable"])
+        .set(expirable as i64);
+    metrics
+        .with_label_values(&["permanent"])
+        .set((streams.len() - expirable) as i64);
+    state
+        .metrics
+        .transcoding_pipelines
+        .set(state.governor.running_pipelines() as i64);
+    if let Some(cpu_percent) = state.governor.cpu_percent() {
+        state.metrics.cpu_usage.set(cpu_percent);
+    }
+    for entry in &streams {
+        state
+            .metrics
+            .stream_clients
+            .with_label_values(&[&entry.id])
+            .set(state.clients.viewer_count(&entry.id) as i64);
     }
 
-    Ok(Json(result))
+    let body = state.metrics.render().map_err(|err| {
+        AppError::InternalError(InternalError {
+            debug_message: format!("could not render metrics: {:?}", err),
+        })
+    })?;
+    Ok((
+        [(http::header::CONTENT_TYPE, state.metrics.content_type())],
+        body,
+    ))
 }
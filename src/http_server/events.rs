@@ -0,0 +1,65 @@
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use futures_util::stream::{self, Stream};
+use serde::Deserialize;
+use tokio::sync::broadcast::error::RecvError;
+
+use super::appstate::AppState;
+
+#[derive(Debug, Deserialize)]
+pub struct StreamEventsQuery {
+    pub stream_id: Option<String>,
+}
+
+/// Server-Sent Events feed of stream lifecycle events, optionally limited to a
+/// single stream with `?stream_id=`. The feed ends when the relay shuts down,
+/// so that open subscribers do not hold up the graceful shutdown.
+pub async fn stream_events(
+    State(state): State<AppState>,
+    Query(query): Query<StreamEventsQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.events.subscribe();
+    let events = stream::unfold(
+        (receiver, state.shutdown.clone(), query.stream_id),
+        |(mut receiver, mut shutdown, stream_id)| async move {
+            loop {
+                let received = tokio::select! {
+                    received = receiver.recv() => received,
+                    _ = shutdown.wait_for(|stopping| *stopping) => return None,
+                };
+                let event = match received {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!(
+                            "event subscriber lagged behind, {} events skipped",
+                            skipped
+                        );
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return None,
+                };
+                if stream_id.as_ref().is_some_and(|id| id != &event.stream_id) {
+                    continue;
+                }
+
+                let sse_event = match Event::default()
+                    .event(event.event_type.as_str())
+                    .json_data(&event)
+                {
+                    Ok(sse_event) => sse_event,
+                    Err(err) => {
+                        tracing::error!("could not serialize event {:?}: {:?}", event, err);
+                        continue;
+                    }
+                };
+                return Some((Ok(sse_event), (receiver, shutdown, stream_id)));
+            }
+        },
+    );
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
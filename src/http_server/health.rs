@@ -0,0 +1,150 @@
+use std::{sync::RwLock, time::Duration};
+
+use axum::{extract::State, http, response::IntoResponse, Json};
+use gst_rtsp_server::{prelude::RTSPServerExt, RTSPServer};
+use serde::Serialize;
+
+use super::appstate::AppState;
+
+const RTSP_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Handles to the parts of the relay the health endpoints look at.
+pub struct HealthState {
+    pub main_loop: glib::MainLoop,
+    pub rtsp_server: RTSPServer,
+    pub rtsp_probe_address: String,
+    default_stream_ids: RwLock<Option<Vec<String>>>,
+}
+
+impl HealthState {
+    pub fn new(
+        main_loop: glib::MainLoop,
+        rtsp_server: RTSPServer,
+        rtsp_probe_address: String,
+    ) -> Self {
+        Self {
+            main_loop,
+            rtsp_server,
+            rtsp_probe_address,
+            default_stream_ids: RwLock::new(None),
+        }
+    }
+
+    /// Records which default cameras are expected to be mounted. Until this is
+    /// called the relay does not report ready.
+    pub fn set_default_stream_ids(&self, ids: Vec<String>) {
+        *self
+            .default_stream_ids
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(ids);
+    }
+
+    fn default_stream_ids(&self) -> Option<Vec<String>> {
+        self.default_stream_ids
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthCheckOutput {
+    pub status: &'static str,
+    pub checks: Vec<HealthCheck>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HealthCheck {
+    pub name: &'static str,
+    pub healthy: bool,
+    pub details: Option<String>,
+}
+
+async fn liveness_checks(state: &AppState) -> Vec<HealthCheck> {
+    let health = &state.health;
+    let bound_port = health.rtsp_server.bound_port();
+    let accepting = tokio::time::timeout(
+        RTSP_PROBE_TIMEOUT,
+        tokio::net::TcpStream::connect(&health.rtsp_probe_address),
+    )
+    .await;
+    let accepting_details = match &accepting {
+        Ok(Ok(_)) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!(
+            "no connection to {} within {:?}",
+            health.rtsp_probe_address, RTSP_PROBE_TIMEOUT
+        )),
+    };
+
+    vec![
+        HealthCheck {
+            name: "main_loop",
+            healthy: health.main_loop.is_running(),
+            details: None,
+        },
+        HealthCheck {
+            name: "rtsp_server_attached",
+            healthy: bound_port > 0,
+            details: Some(format!("bound port {}", bound_port)),
+        },
+        HealthCheck {
+            name: "rtsp_server_accepting",
+            healthy: accepting_details.is_none(),
+            details: accepting_details,
+        },
+    ]
+}
+
+fn default_streams_check(state: &AppState) -> HealthCheck {
+    let Some(expected_ids) = state.health.default_stream_ids() else {
+        return HealthCheck {
+            name: "default_streams",
+            healthy: false,
+            details: Some("default streams are still loading".to_string()),
+        };
+    };
+
+    let missing = expected_ids
+        .iter()
+        .filter(|id| !state.streams.contains(id))
+        .cloned()
+        .collect::<Vec<String>>();
+
+    HealthCheck {
+        name: "default_streams",
+        healthy: missing.is_empty(),
+        details: if missing.is_empty() {
+            None
+        } else {
+            Some(format!("not mounted: {}", missing.join(", ")))
+        },
+    }
+}
+
+fn health_response(checks: Vec<HealthCheck>) -> impl IntoResponse {
+    let healthy = checks.iter().all(|check| check.healthy);
+    let status_code = if healthy {
+        http::StatusCode::OK
+    } else {
+        http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status_code,
+        Json(HealthCheckOutput {
+            status: if healthy { "ok" } else { "unavailable" },
+            checks,
+        }),
+    )
+}
+
+pub async fn healthz(State(state): State<AppState>) -> impl IntoResponse {
+    health_response(liveness_checks(&state).await)
+}
+
+pub async fn readyz(State(state): State<AppState>) -> impl IntoResponse {
+    let mut checks = liveness_checks(&state).await;
+    checks.push(default_streams_check(&state));
+    health_response(checks)
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, State},
+    http, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    appstate::{AppState, StreamInfoInternal},
+    clients::stream_not_found,
+    error::{AppError, UserInputError},
+};
+
+const MAX_LABELS: usize = 64;
+const MAX_TAGS: usize = 64;
+const MAX_NAME_LENGTH: usize = 63;
+const MAX_LABEL_VALUE_LENGTH: usize = 256;
+
+/// Whether `name` can be used as a label key or a tag. Names are kept to
+/// characters that need no escaping in `?label=key=value`.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'))
+}
+
+fn invalid(message: String, field: String) -> AppError {
+    AppError::UserInputError(UserInputError {
+        status_code: http::StatusCode::BAD_REQUEST,
+        message,
+        details: json!({ "field": field }),
+    })
+}
+
+/// Checks the labels and tags of a stream and returns the tags sorted and
+/// without duplicates.
+pub fn validate_labels(
+    labels: &BTreeMap<String, String>,
+    tags: &[String],
+) -> Result<Vec<String>, AppError> {
+    if labels.len() > MAX_LABELS {
+        return Err(invalid(
+            format!("a stream can have at most {} labels", MAX_LABELS),
+            "labels".to_string(),
+        ));
+    }
+    for (key, value) in labels {
+        if !is_valid_name(key) {
+            return Err(invalid(
+                format!(
+                    "label keys must be 1 to {} letters, digits, '-', '_', '.' or '/'",
+                    MAX_NAME_LENGTH
+                ),
+                format!("labels.{}", key),
+            ));
+        }
+        if value.len() > MAX_LABEL_VALUE_LENGTH {
+            return Err(invalid(
+                format!(
+                    "label values can be at most {} bytes",
+                    MAX_LABEL_VALUE_LENGTH
+                ),
+                format!("labels.{}", key),
+            ));
+        }
+    }
+
+    if tags.len() > MAX_TAGS {
+        return Err(invalid(
+            format!("a stream can have at most {} tags", MAX_TAGS),
+            "tags".to_string(),
+        ));
+    }
+    if let Some(index) = tags.iter().position(|tag| !is_valid_name(tag)) {
+        return Err(invalid(
+            format!(
+                "tags must be 1 to {} letters, digits, '-', '_', '.' or '/'",
+                MAX_NAME_LENGTH
+            ),
+            format!("tags[{}]", index),
+        ));
+    }
+    let mut tags = tags.to_vec();
+    tags.sort();
+    tags.dedup();
+    Ok(tags)
+}
+
+/// Drops the labels and tags of a stream `validate_labels` would reject,
+/// with a warning, for streams that come from elsewhere than the API and
+/// should not be refused over their labels.
+pub fn sanitize_labels(
+    stream_id: &str,
+    labels: BTreeMap<String, String>,
+    tags: Vec<String>,
+) -> (BTreeMap<String, String>, Vec<String>) {
+    let mut kept_labels = BTreeMap::new();
+    for (key, value) in labels {
+        if !is_valid_name(&key) || value.len() > MAX_LABEL_VALUE_LENGTH {
+            tracing::warn!("dropping invalid label {:?} of stream {}", key, stream_id);
+        } else if kept_labels.len() == MAX_LABELS {
+            tracing::warn!(
+                "dropping label {:?} of stream {}, it has too many",
+                key,
+                stream_id
+            );
+        } else {
+            kept_labels.insert(key, value);
+        }
+    }
+
+    let mut kept_tags = vec![];
+    for tag in tags {
+        if !is_valid_name(&tag) {
+            tracing::warn!("dropping invalid tag {:?} of stream {}", tag, stream_id);
+        } else if !kept_tags.contains(&tag) {
+            kept_tags.push(tag);
+        }
+    }
+    kept_tags.sort();
+    if kept_tags.len() > MAX_TAGS {
+        tracing::warn!(
+            "dropping {} tags of stream {}, it has too many",
+            kept_tags.len() - MAX_TAGS,
+            stream_id
+        );
+        kept_tags.truncate(MAX_TAGS);
+    }
+    (kept_labels, kept_tags)
+}
+
+/// A `?label=` filter: `key=value` matches streams with that label value,
+/// a bare `key` streams that have the label at all.
+#[derive(Debug, Clone)]
+pub struct LabelSelector {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl LabelSelector {
+    pub fn parse(selector: &str) -> Result<Self, AppError> {
+        let (key, value) = match selector.split_once('=') {
+            Some((key, value)) => (key, Some(value.to_owned())),
+            None => (selector, None),
+        };
+        if !is_valid_name(key) {
+            return Err(AppError::UserInputError(UserInputError {
+                status_code: http::StatusCode::BAD_REQUEST,
+                message: "label filters must be key=value or key".to_string(),
+                details: json!({ "field": "label", "value": selector }),
+            }));
+        }
+        Ok(Self {
+            key: key.to_owned(),
+            value,
+        })
+    }
+
+    pub fn matches(&self, stream: &StreamInfoInternal) -> bool {
+        match (stream.labels.get(&self.key), &self.value) {
+            (Some(actual), Some(wanted)) => actual == wanted,
+            (Some(_), None) => true,
+            (None, _) => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct StreamLabels {
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Replaces the labels and tags of a stream.
+pub async fn put_stream_labels(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(req): Json<StreamLabels>,
+) -> Result<Json<StreamLabels>, AppError> {
+    let tags = validate_labels(&req.labels, &req.tags)?;
+
+    let found = state.streams.update(&id, |stream| {
+        stream.labels = req.labels.clone();
+        stream.tags = tags.clone();
+    });
+    if !found {
+        return Err(stream_not_found(&id));
+    }
+
+    tracing::info!(
+        "setting {} labels and {} tags on stream {}",
+        req.labels.len(),
+        tags.len(),
+        id
+    );
+    Ok(Json(StreamLabels {
+        labels: req.labels,
+        tags,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitizing_drops_invalid_labels_and_tags() {
+        let labels = BTreeMap::from([
+            ("camera model".to_string(), "x".to_string()),
+            ("site".to_string(), "north".to_string()),
+            ("notes".to_string(), "x".repeat(MAX_LABEL_VALUE_LENGTH + 1)),
+        ]);
+        let tags = vec![
+            "outdoor".to_string(),
+            "".to_string(),
+            "entrance".to_string(),
+            "outdoor".to_string(),
+        ];
+        let (labels, tags) = sanitize_labels("cam1", labels, tags);
+        assert_eq!(
+            labels,
+            BTreeMap::from([("site".to_string(), "north".to_string())])
+        );
+        assert_eq!(tags, vec!["entrance".to_string(), "outdoor".to_string()]);
+        assert!(validate_labels(&labels, &tags).is_ok());
+    }
+
+    #[test]
+    fn sanitizing_keeps_within_the_limits() {
+        let labels = (0..MAX_LABELS + 3)
+            .map(|index| (format!("key{:03}", index), String::new()))
+            .collect::<BTreeMap<_, _>>();
+        let tags = (0..MAX_TAGS + 3)
+            .map(|index| format!("tag{:03}", index))
+            .collect::<Vec<_>>();
+        let (labels, tags) = sanitize_labels("cam1", labels, tags);
+        assert_eq!(labels.len(), MAX_LABELS);
+        assert_eq!(tags.len(), MAX_TAGS);
+        assert!(validate_labels(&labels, &tags).is_ok());
+    }
+}
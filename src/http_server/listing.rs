@@ -0,0 +1,439 @@
+use std::sync::Arc;
+
+use axum::http;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    appstate::{ExpirationDate, StreamInfoInternal},
+    error::{AppError, UserInputError},
+    labels::LabelSelector,
+    registry::StreamEntry,
+};
+
+const MAX_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SortField {
+    Name,
+    AddedAt,
+    Expiration,
+}
+
+impl SortField {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "name" => Some(SortField::Name),
+            "added_at" => Some(SortField::AddedAt),
+            "expiration" => Some(SortField::Expiration),
+            _ => None,
+        }
+    }
+
+    fn key(self, stream: &StreamInfoInternal) -> SortKey {
+        match self {
+            SortField::Name => SortKey::Text(stream.name.clone()),
+            SortField::AddedAt => SortKey::Time(stream.added_at.timestamp_micros()),
+            // Streams that never expire come last.
+            SortField::Expiration => SortKey::Time(match stream.expiration_date {
+                ExpirationDate::Never => i64::MAX,
+                ExpirationDate::At(date_time) => date_time.timestamp_micros(),
+            }),
+        }
+    }
+}
+
+/// Value streams are ordered by. Streams with the same one are ordered by id.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(untagged)]
+enum SortKey {
+    Text(String),
+    Time(i64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamKind {
+    Expirable,
+    Permanent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamStatusFilter {
+    Connected,
+    Disconnected,
+    Stalled,
+    Frozen,
+}
+
+/// Where a page ended: the sort key and id of its last stream. Listing goes
+/// on after that stream, so streams added or removed meanwhile do not shift
+/// the following pages.
+#[derive(Debug, Serialize, Deserialize)]
+struct Cursor {
+    sort: SortField,
+    descending: bool,
+    key: SortKey,
+    id: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> Option<String> {
+        let json = serde_json::to_vec(self).ok()?;
+        Some(data_encoding::BASE64URL_NOPAD.encode(&json))
+    }
+
+    fn decode(cursor: &str) -> Option<Self> {
+        let json = data_encoding::BASE64URL_NOPAD
+            .decode(cursor.as_bytes())
+            .ok()?;
+        serde_json::from_slice(&json).ok()
+    }
+}
+
+fn invalid(field: &str, value: &str, message: String) -> AppError {
+    AppError::UserInputError(UserInputError {
+        status_code: http::StatusCode::BAD_REQUEST,
+        message,
+        details: json!({ "field": field, "value": value }),
+    })
+}
+
+/// The filters, order and page `GET /streams` was asked for.
+#[derive(Debug)]
+pub struct StreamListQuery {
+    label_selectors: Vec<LabelSelector>,
+    tags: Vec<String>,
+    kind: Option<StreamKind>,
+    name: Option<String>,
+    status: Option<StreamStatusFilter>,
+    sort: SortField,
+    descending: bool,
+    limit: Option<usize>,
+    cursor: Option<Cursor>,
+}
+
+impl StreamListQuery {
+    /// Parses the query parameters. `label` and `tag` may be repeated, all of
+    /// them have to match.
+    pub fn parse(params: Vec<(String, String)>) -> Result<Self, AppError> {
+        let mut query = StreamListQuery {
+            label_selectors: vec![],
+            tags: vec![],
+            kind: None,
+            name: None,
+            status: None,
+            sort: SortField::AddedAt,
+            descending: false,
+            limit: None,
+            cursor: None,
+        };
+        let mut cursor = None;
+        for (name, value) in params {
+            match name.as_str() {
+                "label" => query.label_selectors.push(LabelSelector::parse(&value)?),
+                "tag" => query.tags.push(value),
+                "kind" => {
+                    query.kind = Some(match value.as_str() {
+                        "expirable" => StreamKind::Expirable,
+                        "permanent" => StreamKind::Permanent,
+                        _ => {
+                            return Err(invalid(
+                                "kind",
+                                &value,
+                                "kind must be expirable or permanent".to_string(),
+                            ))
+                        }
+                    })
+                }
+                "name" => query.name = Some(value.to_lowercase()),
+                "status" => {
+                    query.status = Some(match value.as_str() {
+                        "connected" => StreamStatusFilter::Connected,
+                        "disconnected" => StreamStatusFilter::Disconnected,
+                        "stalled" => StreamStatusFilter::Stalled,
+                        "frozen" => StreamStatusFilter::Frozen,
+                        _ => {
+                            return Err(invalid(
+                                "status",
+                                &value,
+                                "status must be connected, disconnected, stalled or frozen"
+                                    .to_string(),
+                            ))
+                        }
+                    })
+                }
+                "sort" => {
+                    query.sort = SortField::parse(&value).ok_or_else(|| {
+                        invalid(
+                            "sort",
+                            &value,
+                            "sort must be name, added_at or expiration".to_string(),
+                        )
+                    })?
+                }
+                "order" => {
+                    query.descending = match value.as_str() {
+                        "asc" => false,
+                        "desc" => true,
+                        _ => {
+                            return Err(invalid(
+                                "order",
+                                &value,
+                                "order must be asc or desc".to_string(),
+                            ))
+                        }
+                    }
+                }
+                "limit" => {
+                    query.limit = Some(
+                        value
+                            .parse::<usize>()
+                            .ok()
+                            .filter(|limit| (1..=MAX_LIMIT).contains(limit))
+                            .ok_or_else(|| {
+                                invalid(
+                                    "limit",
+                                    &value,
+                                    format!("limit must be between 1 and {}", MAX_LIMIT),
+                                )
+                            })?,
+                    )
+                }
+                "cursor" => cursor = Some(value),
+                _ => {
+                    return Err(AppError::UserInputError(UserInputError {
+                        status_code: http::StatusCode::BAD_REQUEST,
+                        message: format!("unknown query parameter {}", name),
+                        details: json!({ "field": name }),
+                    }))
+                }
+            }
+        }
+
+        // The cursor is checked last, against the order it has to continue.
+        if let Some(value) = cursor {
+            let cursor = Cursor::decode(&value)
+                .filter(|cursor| cursor.sort == query.sort && cursor.descending == query.descending)
+                .ok_or_else(|| {
+                    invalid(
+                        "cursor",
+                        &value,
+                        "cursor is invalid or was not returned for this sort and order".to_string(),
+                    )
+                })?;
+            query.cursor = Some(cursor);
+        }
+        Ok(query)
+    }
+
+    fn matches(&self, stream: &StreamInfoInternal) -> bool {
+        if !self
+            .label_selectors
+            .iter()
+            .all(|selector| selector.matches(stream))
+            || !self.tags.iter().all(|tag| stream.tags.contains(tag))
+        {
+            return false;
+        }
+        if let Some(kind) = self.kind {
+            let expirable = matches!(stream.expiration_date, ExpirationDate::At(_));
+            if expirable != (kind == StreamKind::Expirable) {
+                return false;
+            }
+        }
+        if let Some(name) = &self.name {
+            if !stream.name.to_lowercase().contains(name) {
+                return false;
+            }
+        }
+        if let Some(status) = self.status {
+            let runtime = stream.runtime.snapshot();
+            let matches = match status {
+                StreamStatusFilter::Connected => runtime.upstream_connected,
+                StreamStatusFilter::Disconnected => !runtime.upstream_connected,
+                StreamStatusFilter::Stalled => runtime.stalled,
+                StreamStatusFilter::Frozen => runtime.frozen,
+            };
+            if !matches {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Filters and sorts `streams` and cuts out the page asked for. Returns
+    /// the cursor of the next page when there is one.
+    pub fn page(&self, streams: Vec<Arc<StreamEntry>>) -> (Vec<Arc<StreamEntry>>, Option<String>) {
+        let streams = streams
+            .into_iter()
+            .filter_map(|entry| {
+                let stream = entry.info();
+                self.matches(&stream)
+                    .then(|| (self.sort.key(&stream), entry.id.clone(), entry))
+            })
+            .collect();
+        self.paginate(streams)
+    }
+
+    /// Sorts `items` by their sort key and id and cuts out the page asked for.
+    fn paginate<T>(&self, mut items: Vec<(SortKey, String, T)>) -> (Vec<T>, Option<String>) {
+        items.sort_by(|(a_key, a_id, _), (b_key, b_id, _)| (a_key, a_id).cmp(&(b_key, b_id)));
+        if self.descending {
+            items.reverse();
+        }
+
+        let start = match &self.cursor {
+            Some(cursor) => items
+                .iter()
+                .position(|(key, id, _)| {
+                    let position = (key, id).cmp(&(&cursor.key, &cursor.id));
+                    if self.descending {
+                        position.is_lt()
+                    } else {
+                        position.is_gt()
+                    }
+                })
+                .unwrap_or(items.len()),
+            None => 0,
+        };
+        let end = match self.limit {
+            Some(limit) => items.len().min(start + limit),
+            None => items.len(),
+        };
+
+        let next_cursor = if start < end && end < items.len() {
+            items.get(end - 1).and_then(|(key, id, _)| {
+                Cursor {
+                    sort: self.sort,
+                    descending: self.descending,
+                    key: key.clone(),
+                    id: id.clone(),
+                }
+                .encode()
+            })
+        } else {
+            None
+        };
+        let page = items.drain(start..end).map(|(_, _, item)| item).collect();
+        (page, next_cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn items(keys: &[(i64, &str)]) -> Vec<(SortKey, String, String)> {
+        keys.iter()
+            .map(|(key, id)| (SortKey::Time(*key), id.to_string(), id.to_string()))
+            .collect()
+    }
+
+    /// Follows the cursors from the first page to the last one.
+    fn all_pages(pairs: &[(&str, &str)], keys: &[(i64, &str)]) -> Vec<Vec<String>> {
+        let mut pages = vec![];
+        let mut cursor = None;
+        loop {
+            let mut query_params = params(pairs);
+            if let Some(cursor) = cursor.take() {
+                query_params.push(("cursor".to_string(), cursor));
+            }
+            let query = StreamListQuery::parse(query_params).unwrap();
+            let (page, next_cursor) = query.paginate(items(keys));
+            pages.push(page);
+            match next_cursor {
+                Some(next_cursor) => cursor = Some(next_cursor),
+                None => return pages,
+            }
+        }
+    }
+
+    #[test]
+    fn pages_follow_the_sort_key_and_then_the_id() {
+        let keys = [(3, "e"), (1, "b"), (2, "c"), (1, "a"), (2, "d")];
+        assert_eq!(
+            all_pages(&[("limit", "2")], &keys),
+            vec![vec!["a", "b"], vec!["c", "d"], vec!["e"]]
+        );
+    }
+
+    #[test]
+    fn descending_pages_reverse_key_and_id() {
+        let keys = [(3, "e"), (1, "b"), (2, "c"), (1, "a"), (2, "d")];
+        assert_eq!(
+            all_pages(&[("order", "desc"), ("limit", "2")], &keys),
+            vec![vec!["e", "d"], vec!["c", "b"], vec!["a"]]
+        );
+    }
+
+    #[test]
+    fn ties_do_not_repeat_or_skip_across_pages() {
+        let keys = [(1, "c"), (1, "a"), (1, "d"), (1, "b")];
+        assert_eq!(
+            all_pages(&[("limit", "3")], &keys),
+            vec![vec!["a", "b", "c"], vec!["d"]]
+        );
+        assert_eq!(
+            all_pages(&[("order", "desc"), ("limit", "1")], &keys),
+            vec![vec!["d"], vec!["c"], vec!["b"], vec!["a"]]
+        );
+    }
+
+    #[test]
+    fn a_full_last_page_has_no_next_cursor() {
+        let keys = [(1, "a"), (2, "b")];
+        assert_eq!(all_pages(&[("limit", "2")], &keys), vec![vec!["a", "b"]]);
+        assert_eq!(all_pages(&[], &keys), vec![vec!["a", "b"]]);
+    }
+
+    #[test]
+    fn cursors_carry_on_after_removed_streams() {
+        let query = StreamListQuery::parse(params(&[("limit", "2")])).unwrap();
+        let (_, cursor) = query.paginate(items(&[(1, "a"), (2, "b"), (3, "c"), (4, "d")]));
+        let mut query_params = params(&[("limit", "2")]);
+        query_params.push(("cursor".to_string(), cursor.unwrap()));
+        let query = StreamListQuery::parse(query_params).unwrap();
+        // The last stream of the first page is gone by now.
+        let (page, _) = query.paginate(items(&[(1, "a"), (3, "c"), (4, "d")]));
+        assert_eq!(page, vec!["c", "d"]);
+    }
+
+    #[test]
+    fn invalid_cursors_are_rejected() {
+        assert!(StreamListQuery::parse(params(&[("cursor", "not a cursor")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("cursor", "e30")])).is_err());
+
+        let query = StreamListQuery::parse(params(&[("limit", "1")])).unwrap();
+        let (_, cursor) = query.paginate(items(&[(1, "a"), (2, "b")]));
+        let cursor = cursor.unwrap();
+        // A cursor only continues the sort and order it was made for.
+        assert!(StreamListQuery::parse(params(&[("cursor", &cursor)])).is_ok());
+        assert!(StreamListQuery::parse(params(&[("order", "desc"), ("cursor", &cursor)])).is_err());
+        assert!(StreamListQuery::parse(params(&[("sort", "name"), ("cursor", &cursor)])).is_err());
+    }
+
+    #[test]
+    fn limits_are_bounded() {
+        assert!(StreamListQuery::parse(params(&[("limit", "1")])).is_ok());
+        assert!(StreamListQuery::parse(params(&[("limit", "1000")])).is_ok());
+        assert!(StreamListQuery::parse(params(&[("limit", "0")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("limit", "1001")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("limit", "-1")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("limit", "ten")])).is_err());
+    }
+
+    #[test]
+    fn unknown_parameters_are_rejected() {
+        assert!(StreamListQuery::parse(params(&[("page", "2")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("sort", "size")])).is_err());
+        assert!(StreamListQuery::parse(params(&[("order", "up")])).is_err());
+    }
+}
@@ -0,0 +1,29 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+
+use crate::rtsp_server::masks::PrivacyMask;
+
+use super::{
+    appstate::AppState, clients::stream_not_found, endpoints::validate_masks, error::AppError,
+};
+
+/// Replaces the privacy masks of a stream. Running pipelines pick them up
+/// with the next frame.
+pub async fn put_stream_masks(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    Json(masks): Json<Vec<PrivacyMask>>,
+) -> Result<Json<Vec<PrivacyMask>>, AppError> {
+    let stream = state
+        .streams
+        .get(&id)
+        .ok_or_else(|| stream_not_found(&id))?
+        .info();
+    validate_masks(&masks, stream.down_scale)?;
+
+    tracing::info!("setting {} privacy masks on stream {}", masks.len(), id);
+    stream.masks.set(masks);
+    Ok(Json(stream.masks.get()))
+}
@@ -0,0 +1,31 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use super::appstate::AppState;
+
+pub async fn track_http_metrics(
+    State(state): State<AppState>,
+    matched_path: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let method = request.method().to_string();
+    let path = matched_path
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or_else(|| "unmatched".to_owned());
+    let started_at = Instant::now();
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .http_request_duration
+        .with_label_values(&[&method, &path, response.status().as_str()])
+        .observe(started_at.elapsed().as_secs_f64());
+    response
+}
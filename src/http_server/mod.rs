@@ -1,4 +1,15 @@
 pub mod endpoints;
 pub mod appstate;
+pub mod clients;
 pub mod setup;
-pub mod error;
\ No newline at end of file
+pub mod shutdown;
+pub mod error;
+pub mod events;
+pub mod health;
+pub mod labels;
+pub mod listing;
+pub mod masks;
+pub mod middleware;
+pub mod registry;
+pub mod source_validation;
+pub mod webhooks;
\ No newline at end of file
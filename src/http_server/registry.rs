@@ -0,0 +1,286 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use glib::object::ObjectExt;
+use gst_rtsp_server::{prelude::RTSPMountPointsExt, RTSPMedia, RTSPMountPoints};
+
+use crate::rtsp_server::{
+    access::{ClientAccessControl, ClientIpPolicy},
+    clients::ClientTracker,
+    factory::RelayMediaFactory,
+    governor::{TranscodeGovernor, TranscodeRejection},
+    lifecycle::MediaLifecycle,
+};
+
+use super::appstate::StreamInfoInternal;
+
+#[derive(Default)]
+struct MediaRefs {
+    medias: Vec<glib::WeakRef<RTSPMedia>>,
+    configured: bool,
+}
+
+/// Medias the factory of one stream created. Written from GStreamer threads
+/// when a media is configured, so it is only ever locked briefly and never
+/// across an await point.
+#[derive(Clone, Default)]
+pub struct StreamMedias {
+    refs: Arc<Mutex<MediaRefs>>,
+}
+
+impl StreamMedias {
+    fn lock(&self) -> std::sync::MutexGuard<'_, MediaRefs> {
+        self.refs
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Records a new media, dropping the references to medias that are gone.
+    pub fn register(&self, media: &RTSPMedia) {
+        let mut refs = self.lock();
+        refs.medias
+            .retain(|weak_media| weak_media.upgrade().is_some());
+        refs.medias.push(media.downgrade());
+        refs.configured = true;
+    }
+
+    /// Whether a media was ever created, i.e. someone played the stream.
+    pub fn was_configured(&self) -> bool {
+        self.lock().configured
+    }
+
+    pub fn medias(&self) -> Vec<RTSPMedia> {
+        let mut refs = self.lock();
+        refs.medias
+            .retain(|weak_media| weak_media.upgrade().is_some());
+        refs.medias
+            .iter()
+            .filter_map(|weak_media| weak_media.upgrade())
+            .collect()
+    }
+}
+
+/// A mounted stream: its metadata, the factory serving it and the medias the
+/// factory created. Metadata is locked per stream so updating one stream does
+/// not block the others.
+pub struct StreamEntry {
+    pub id: String,
+    pub path: String,
+    pub factory: RelayMediaFactory,
+    pub medias: StreamMedias,
+    pub lifecycle: Arc<MediaLifecycle>,
+    info: Mutex<StreamInfoInternal>,
+}
+
+impl StreamEntry {
+    pub fn info(&self) -> StreamInfoInternal {
+        self.info
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+}
+
+#[derive(Debug)]
+pub enum StreamAddError {
+    AlreadyExists { id: String },
+    Transcoding(TranscodeRejection),
+}
+
+/// Every mounted stream, keyed by id. Mounting, the access policy, the viewer
+/// limit and the registry entry are changed together under the registry lock,
+/// which is never held across an await point, so there is no lock order to get
+/// wrong. Transcoded streams are admitted under it too, so concurrent adds can
+/// not overshoot the limit.
+pub struct StreamRegistry {
+    streams: RwLock<HashMap<String, Arc<StreamEntry>>>,
+    mounts: RTSPMountPoints,
+    access_control: Arc<ClientAccessControl>,
+    clients: Arc<ClientTracker>,
+    governor: Arc<TranscodeGovernor>,
+}
+
+impl StreamRegistry {
+    pub fn new(
+        mounts: RTSPMountPoints,
+        access_control: Arc<ClientAccessControl>,
+        clients: Arc<ClientTracker>,
+        governor: Arc<TranscodeGovernor>,
+    ) -> Self {
+        Self {
+            streams: RwLock::new(HashMap::new()),
+            mounts,
+            access_control,
+            clients,
+            governor,
+        }
+    }
+
+    /// Mounts `factory` at `/{id}`, registers the stream and brings it up if
+    /// it is always on. Transcoded streams have to be admitted by the governor.
+    pub fn add(
+        &self,
+        info: StreamInfoInternal,
+        factory: RelayMediaFactory,
+        medias: StreamMedias,
+        lifecycle: Arc<MediaLifecycle>,
+        client_ip_policy: ClientIpPolicy,
+    ) -> Result<Arc<StreamEntry>, StreamAddError> {
+        let mut streams = self
+            .streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if streams.contains_key(&info.id) {
+            return Err(StreamAddError::AlreadyExists { id: info.id });
+        }
+        self.insert(
+            &mut streams,
+            info,
+            factory,
+            medias,
+            lifecycle,
+            client_ip_policy,
+        )
+    }
+
+    /// Like `add`, but takes the place of the stream with the same id if there
+    /// is one. That stream is returned for the caller to stop; it is left
+    /// untouched when the new one is not admitted.
+    pub fn replace(
+        &self,
+        info: StreamInfoInternal,
+        factory: RelayMediaFactory,
+        medias: StreamMedias,
+        lifecycle: Arc<MediaLifecycle>,
+        client_ip_policy: ClientIpPolicy,
+    ) -> Result<(Arc<StreamEntry>, Option<Arc<StreamEntry>>), StreamAddError> {
+        let mut streams = self
+            .streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let replaced = streams.get(&info.id).cloned();
+        let entry = self.insert(
+            &mut streams,
+            info,
+            factory,
+            medias,
+            lifecycle,
+            client_ip_policy,
+        )?;
+        Ok((entry, replaced))
+    }
+
+    fn insert(
+        &self,
+        streams: &mut HashMap<String, Arc<StreamEntry>>,
+        info: StreamInfoInternal,
+        factory: RelayMediaFactory,
+        medias: StreamMedias,
+        lifecycle: Arc<MediaLifecycle>,
+        client_ip_policy: ClientIpPolicy,
+    ) -> Result<Arc<StreamEntry>, StreamAddError> {
+        if info.down_scale {
+            let transcodes = streams
+                .values()
+                .filter(|entry| entry.id != info.id && entry.info().down_scale)
+                .count();
+            self.governor
+                .admit(transcodes)
+                .map_err(StreamAddError::Transcoding)?;
+        }
+
+        let max_clients = info.max_clients.map(|max_clients| max_clients as usize);
+        let entry = Arc::new(StreamEntry {
+            id: info.id.clone(),
+            path: format!("/{}", info.id),
+            factory,
+            medias,
+            lifecycle,
+            info: Mutex::new(info),
+        });
+        // Mounting a factory at a path that is taken replaces the old one.
+        self.access_control
+            .set_stream_policy(&entry.path, client_ip_policy);
+        self.clients
+            .add_stream(&entry.id, max_clients, entry.lifecycle.clone());
+        self.mounts.add_factory(&entry.path, entry.factory.clone());
+        streams.insert(entry.id.clone(), entry.clone());
+        entry.lifecycle.start();
+
+        Ok(entry)
+    }
+
+    /// Unmounts and unregisters a stream. Stopping its lifecycle and its medias
+    /// is left to the caller.
+    pub fn remove(&self, id: &str) -> Option<Arc<StreamEntry>> {
+        self.remove_if(id, |_| true)
+    }
+
+    /// Removes a stream only when `predicate` holds, checked under the same
+    /// lock so no client can sneak in between the check and the removal.
+    pub fn remove_if(
+        &self,
+        id: &str,
+        predicate: impl FnOnce(&StreamEntry) -> bool,
+    ) -> Option<Arc<StreamEntry>> {
+        let mut streams = self
+            .streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if !predicate(streams.get(id)?.as_ref()) {
+            return None;
+        }
+
+        let entry = streams.remove(id)?;
+        self.mounts.remove_factory(&entry.path);
+        self.access_control.remove_stream_policy(&entry.path);
+        self.clients.remove_stream(&entry.id);
+
+        Some(entry)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Arc<StreamEntry>> {
+        self.streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(id)
+            .cloned()
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .contains_key(id)
+    }
+
+    /// Every stream, oldest first.
+    pub fn list(&self) -> Vec<Arc<StreamEntry>> {
+        let mut streams = self
+            .streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .cloned()
+            .collect::<Vec<Arc<StreamEntry>>>();
+        streams.sort_by_cached_key(|entry| entry.info().added_at);
+        streams
+    }
+
+    /// Changes the metadata of one stream. Returns `false` when it is not
+    /// registered.
+    pub fn update(&self, id: &str, update: impl FnOnce(&mut StreamInfoInternal)) -> bool {
+        let Some(entry) = self.get(id) else {
+            return false;
+        };
+        update(
+            &mut entry
+                .info
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        true
+    }
+}
@@ -1,21 +1,37 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use aws_config::BehaviorVersion;
 use axum::{
+    middleware,
     routing::{delete, get, post, put},
     Router,
 };
 
 use crate::{
     config::{implementation::AWSCameraConfigRepository, interface::CameraConfigRepository},
+    events::EventBus,
     http_server::{
         appstate::AppState,
+        clients::{disconnect_clients, disconnect_stream_client, list_stream_clients},
         endpoints::{
-            add_stream, add_stream_to_state, list_streams, put_permanent_stream,
-            remove_stale_streams, remove_stream, AddStreamInput, AddStreamToStateInput,
+            add_stream, add_stream_to_state, export_metrics, get_stream, list_streams,
+            put_permanent_stream, remove_stale_streams, remove_stream, AddStreamInput,
+            AddStreamToStateInput,
         },
+        events::stream_events,
+        health::{healthz, readyz, HealthState},
+        labels::{put_stream_labels, sanitize_labels},
+        masks::put_stream_masks,
+        middleware::track_http_metrics,
+        shutdown::{drain_rtsp_clients, persist_streams, restore_streams, shutdown_signal},
+        source_validation::SourceAllowlist,
+        webhooks::{add_webhook, list_webhook_deliveries, list_webhooks, remove_webhook},
     },
-    rtsp_server::{load_rtsp_server_config, start_server},
+    metrics::RelayMetrics,
+    rtsp_server::{
+        lifecycle::StreamMode, load_rtsp_server_config, start_server, transform::VideoTransform,
+    },
+    webhooks::{run_dispatcher, WebhookRegistry},
 };
 
 #[derive(Debug, Clone)]
@@ -37,6 +53,12 @@ struct ServerConfig {
     pub load_default_streams: bool,
     pub table_name: String,
     pub partition_key: String,
+    pub source_allowlist: SourceAllowlist,
+    pub webhook_urls: Vec<String>,
+    pub webhook_allowlist: SourceAllowlist,
+    pub webhook_secret: Option<String>,
+    pub shutdown_deadline_in_seconds: u64,
+    pub state_file: Option<String>,
 }
 fn read_config() -> Result<ServerConfig, ReadConfigErr> {
     let http_port: i32 = std::env::var("HTTP_PORT")
@@ -90,6 +112,45 @@ fn read_config() -> Result<ServerConfig, ReadConfigErr> {
         reason: "PARTITION_KEY not set or invalid".to_string(),
     })?;
 
+    let source_allowlist = match std::env::var("SOURCE_ALLOWLIST") {
+        Ok(value) => SourceAllowlist::parse(&value).map_err(|err| ReadConfigErr {
+            reason: format!("SOURCE_ALLOWLIST is invalid: {}", err.reason),
+        })?,
+        Err(_) => SourceAllowlist::default(),
+    };
+
+    let webhook_urls = std::env::var("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_owned)
+        .collect::<Vec<String>>();
+    let webhook_allowlist = match std::env::var("WEBHOOK_ALLOWLIST") {
+        Ok(value) => SourceAllowlist::parse(&value).map_err(|err| ReadConfigErr {
+            reason: format!("WEBHOOK_ALLOWLIST is invalid: {}", err.reason),
+        })?,
+        Err(_) => SourceAllowlist::default(),
+    };
+    let webhook_secret = std::env::var("WEBHOOK_SECRET")
+        .ok()
+        .filter(|secret| !secret.is_empty());
+    if !webhook_urls.is_empty() && webhook_secret.is_none() {
+        return Err(ReadConfigErr {
+            reason: "WEBHOOK_SECRET must be set when WEBHOOK_URLS is set".to_string(),
+        });
+    }
+
+    let shutdown_deadline_in_seconds: u64 = match std::env::var("SHUTDOWN_DEADLINE_IN_SECONDS") {
+        Ok(value) => value.parse().map_err(|_| ReadConfigErr {
+            reason: "SHUTDOWN_DEADLINE_IN_SECONDS must be a valid integer".to_string(),
+        })?,
+        Err(_) => 30,
+    };
+    let state_file = std::env::var("STATE_FILE")
+        .ok()
+        .filter(|state_file| !state_file.is_empty());
+
     Ok(ServerConfig {
         http_port,
         http_host,
@@ -99,6 +160,12 @@ fn read_config() -> Result<ServerConfig, ReadConfigErr> {
         table_name,
         partition_key,
         stream_max_life_time_in_minutes,
+        source_allowlist,
+        webhook_urls,
+        webhook_allowlist,
+        webhook_secret,
+        shutdown_deadline_in_seconds,
+        state_file,
     })
 }
 
@@ -116,15 +183,50 @@ pub async fn setup_and_run() -> Result<(), StartupServerError> {
     let rtsp_server_config = load_rtsp_server_config().map_err(|err| StartupServerError {
         reason: format!("Failed to load RTSP server config: {:?}", err),
     })?;
-    let mount_points = start_server(rtsp_server_config).map_err(|err| StartupServerError {
-        reason: format!("Failed to start RTSP server: {:?}", err),
+    let events = EventBus::default();
+    let mount_points =
+        start_server(rtsp_server_config, events.clone()).map_err(|err| StartupServerError {
+            reason: format!("Failed to start RTSP server: {:?}", err),
+        })?;
+    let relay_metrics = RelayMetrics::new().map_err(|err| StartupServerError {
+        reason: format!("Failed to register metrics: {:?}", err),
     })?;
+    let rtsp_source_id = mount_points.source_id;
+    let main_loop = glib::MainLoop::new(None, false);
+    let health = HealthState::new(
+        main_loop.clone(),
+        mount_points.server,
+        mount_points.probe_address,
+    );
+    let webhooks = Arc::new(
+        WebhookRegistry::new(server_config.webhook_secret).map_err(|err| StartupServerError {
+            reason: format!("Failed to set up webhooks: {}", err.reason),
+        })?,
+    );
+    for url in server_config.webhook_urls {
+        webhooks.register(url, vec![], None);
+    }
+    tokio::spawn(run_dispatcher(webhooks.clone(), events.clone()));
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
     let app_state = AppState::new(
         server_config.stream_expiration_time_in_minutes,
         &server_config.root_url,
         &mount_points.root_url.clone().to_owned(),
         mount_points.mount_points,
         server_config.stream_max_life_time_in_minutes,
+        server_config.source_allowlist,
+        server_config.webhook_allowlist,
+        mount_points.slate,
+        mount_points.overlay_image_dir,
+        mount_points.codecs,
+        mount_points.governor,
+        mount_points.access_control,
+        mount_points.clients,
+        Arc::new(relay_metrics),
+        Arc::new(health),
+        events,
+        webhooks,
+        shutdown_receiver.clone(),
     );
 
     if server_config.load_default_streams {
@@ -144,15 +246,39 @@ pub async fn setup_and_run() -> Result<(), StartupServerError> {
 
         let add_stream_inputs = cameras
             .into_iter()
-            .map(|e| AddStreamToStateInput {
-                id: e.id.clone(),
-                name: e.id,
-                down_scale: false,
-                source_url: e.source_url,
-                expirable: false,
+            .map(|e| {
+                // A bad label must not keep the camera from being relayed.
+                let (labels, tags) = sanitize_labels(&e.id, e.labels, e.tags);
+                AddStreamToStateInput {
+                    id: e.id.clone(),
+                    name: e.id,
+                    down_scale: false,
+                    source_url: e.source_url,
+                    labels,
+                    tags,
+                    backup_source_urls: vec![],
+                    stall_timeout_seconds: None,
+                    slate: false,
+                    transform: VideoTransform::default(),
+                    max_framerate: None,
+                    keyframes_only: false,
+                    overlays: vec![],
+                    masks: vec![],
+                    expirable: false,
+                    expires_at: None,
+                    allowed_client_ips: vec![],
+                    denied_client_ips: vec![],
+                    max_clients: None,
+                    mode: StreamMode::default(),
+                    linger_seconds: 0,
+                }
             })
             .collect::<Vec<AddStreamToStateInput>>();
 
+        let default_stream_ids = add_stream_inputs
+            .iter()
+            .map(|input| input.id.clone())
+            .collect::<Vec<String>>();
         for add_stream_input in add_stream_inputs {
             add_stream_to_state(app_state.clone(), add_stream_input)
                 .await
@@ -160,15 +286,47 @@ pub async fn setup_and_run() -> Result<(), StartupServerError> {
                     reason: format!("Failed to add default stream: {:?}", e),
                 })?;
         }
+        app_state.health.set_default_stream_ids(default_stream_ids);
+    } else {
+        app_state.health.set_default_stream_ids(vec![]);
+    }
+
+    if let Some(state_file) = &server_config.state_file {
+        restore_streams(&app_state, state_file)
+            .await
+            .map_err(|err| StartupServerError {
+                reason: format!("Failed to restore streams: {:?}", err),
+            })?;
     }
 
     let app = Router::new()
         .route("/streams", post(add_stream))
         .route("/streams", get(list_streams))
+        .route("/streams/{id}", get(get_stream))
         .route("/streams/{id}", delete(remove_stream))
+        .route("/streams/{id}/clients", get(list_stream_clients))
+        .route(
+            "/streams/{id}/clients/{client_id}",
+            delete(disconnect_stream_client),
+        )
+        .route("/clients", delete(disconnect_clients))
+        .route("/streams/{id}/masks", put(put_stream_masks))
+        .route("/streams/{id}/labels", put(put_stream_labels))
         .route("/streams/permanent/{id}", put(put_permanent_stream))
         .route("/streams/stale", delete(remove_stale_streams))
-        .with_state(app_state);
+        .route("/metrics", get(export_metrics))
+        .route("/events", get(stream_events))
+        .route("/webhooks", post(add_webhook))
+        .route("/webhooks", get(list_webhooks))
+        .route("/webhooks/{id}", delete(remove_webhook))
+        .route("/webhooks/deliveries", get(list_webhook_deliveries))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            track_http_metrics,
+        ))
+        .with_state(app_state.clone());
     let bind_str = format!("{}:{}", server_config.http_host, server_config.http_port);
 
     tracing::info!("Starting server on {}", bind_str);
@@ -178,16 +336,67 @@ pub async fn setup_and_run() -> Result<(), StartupServerError> {
             reason: format!("Failed to bind to {}: {:?}", bind_str, err),
         })?;
 
-    tokio::spawn(async {
-        tracing::info!("initializing main loop");
-        let main_loop = glib::MainLoop::new(None, false);
-        main_loop.run();
-    });
-
-    axum::serve(listener, app)
-        .await
+    // The main loop blocks its thread for as long as it runs, so it gets one of
+    // its own instead of tying up a tokio worker. The thread is never joined: a
+    // wedged loop must not keep the relay from exiting after the deadline.
+    let glib_main_loop = main_loop.clone();
+    let (main_loop_done_sender, main_loop_done) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("glib-main-loop".to_string())
+        .spawn(move || {
+            tracing::info!("initializing main loop");
+            glib_main_loop.run();
+            let _ = main_loop_done_sender.send(());
+        })
         .map_err(|err| StartupServerError {
-            reason: format!("Server error: {:?}", err),
+            reason: format!("Failed to start the main loop thread: {:?}", err),
         })?;
+
+    let mut shutdown_receiver = shutdown_receiver;
+    let mut server = tokio::spawn(async move {
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                let _ = shutdown_receiver.changed().await;
+            })
+            .await
+    });
+
+    tokio::select! {
+        result = &mut server => {
+            return match result {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(err)) => Err(StartupServerError {
+                    reason: format!("Server error: {:?}", err),
+                }),
+                Err(err) => Err(StartupServerError {
+                    reason: format!("Server task failed: {:?}", err),
+                }),
+            };
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    let deadline = Duration::from_secs(server_config.shutdown_deadline_in_seconds);
+    tracing::info!("shutdown requested, draining within {:?}", deadline);
+    let drain = async {
+        if let Some(state_file) = &server_config.state_file {
+            if let Err(err) = persist_streams(&app_state, state_file).await {
+                tracing::error!("could not persist streams: {}", err.reason);
+            }
+        }
+        // Stop accepting new RTSP connections before closing the existing ones.
+        rtsp_source_id.remove();
+        let _ = shutdown_sender.send(true);
+        drain_rtsp_clients(&app_state).await;
+        main_loop.quit();
+        let _ = server.await;
+        if main_loop_done.await.is_err() {
+            tracing::error!("the main loop thread panicked");
+        }
+    };
+    if tokio::time::timeout(deadline, drain).await.is_err() {
+        tracing::warn!("shutdown deadline exceeded, exiting anyway");
+    }
+
     Ok(())
 }
@@ -0,0 +1,191 @@
+use gst_rtsp_server::{
+    prelude::{RTSPClientExt, RTSPMediaExt, RTSPServerExt},
+    RTSPFilterResult,
+};
+use tokio::io::AsyncWriteExt;
+
+use super::{
+    appstate::{AppState, ExpirationDate},
+    endpoints::{add_stream_to_state, validate_backup_source_urls, AddStreamToStateInput},
+    error::AppError,
+    source_validation::validate_source_url,
+};
+
+#[derive(Debug)]
+pub struct PersistStateError {
+    pub reason: String,
+}
+
+/// Resolves on SIGINT or SIGTERM.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!("could not listen for ctrl-c: {:?}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                tracing::error!("could not listen for SIGTERM: {:?}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Writes `payload` to a file only its owner can read, the streams it holds
+/// carry camera credentials in their source URLs.
+async fn write_private_file(path: &str, payload: &[u8]) -> std::io::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path).await?;
+    // The mode only applies to new files, a file left by an older version
+    // keeps its permissions otherwise.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))
+            .await?;
+    }
+    file.write_all(payload).await?;
+    file.flush().await
+}
+
+/// Checks the source URLs of a saved stream like the API checks them, the
+/// allowlist may have changed since the stream was saved.
+async fn validate_saved_stream(
+    state: &AppState,
+    mut stream: AddStreamToStateInput,
+) -> Result<AddStreamToStateInput, AppError> {
+    stream.source_url = validate_source_url(&stream.source_url, &state.source_allowlist)
+        .await?
+        .to_string();
+    stream.backup_source_urls =
+        validate_backup_source_urls(&stream.backup_source_urls, state).await?;
+    Ok(stream)
+}
+
+pub async fn persist_streams(state: &AppState, state_file: &str) -> Result<(), PersistStateError> {
+    let streams = state
+        .streams
+        .list()
+        .iter()
+        .map(|entry| entry.info())
+        .map(|stream| AddStreamToStateInput {
+            id: stream.id.clone(),
+            name: stream.name.clone(),
+            source_url: stream.source_url.clone(),
+            labels: stream.labels.clone(),
+            tags: stream.tags.clone(),
+            backup_source_urls: stream.backup_source_urls.clone(),
+            stall_timeout_seconds: stream.stall_timeout_seconds,
+            slate: stream.slate,
+            transform: stream.transform,
+            max_framerate: stream.max_framerate,
+            keyframes_only: stream.keyframes_only,
+            overlays: stream.overlays.clone(),
+            masks: stream.masks.get(),
+            down_scale: stream.down_scale,
+            expirable: matches!(stream.expiration_date, ExpirationDate::At(_)),
+            expires_at: match stream.expiration_date {
+                ExpirationDate::Never => None,
+                ExpirationDate::At(date_time) => Some(date_time),
+            },
+            allowed_client_ips: stream.allowed_client_ips.clone(),
+            denied_client_ips: stream.denied_client_ips.clone(),
+            max_clients: stream.max_clients,
+            mode: stream.mode,
+            linger_seconds: stream.linger_seconds,
+        })
+        .collect::<Vec<AddStreamToStateInput>>();
+
+    let payload = serde_json::to_vec_pretty(&streams).map_err(|err| PersistStateError {
+        reason: format!("could not serialize streams: {:?}", err),
+    })?;
+    write_private_file(state_file, &payload)
+        .await
+        .map_err(|err| PersistStateError {
+            reason: format!("could not write {}: {:?}", state_file, err),
+        })?;
+
+    tracing::info!("persisted {} streams to {}", streams.len(), state_file);
+    Ok(())
+}
+
+/// Mounts the streams saved by a previous shutdown that are not mounted yet
+/// and have not expired meanwhile. A missing file is not an error, it just
+/// means there is nothing to restore.
+pub async fn restore_streams(state: &AppState, state_file: &str) -> Result<(), PersistStateError> {
+    let payload = match tokio::fs::read(state_file).await {
+        Ok(payload) => payload,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => {
+            return Err(PersistStateError {
+                reason: format!("could not read {}: {:?}", state_file, err),
+            })
+        }
+    };
+    let streams: Vec<AddStreamToStateInput> =
+        serde_json::from_slice(&payload).map_err(|err| PersistStateError {
+            reason: format!("could not parse {}: {:?}", state_file, err),
+        })?;
+
+    for stream in streams {
+        if state.streams.contains(&stream.id) {
+            continue;
+        }
+        if stream
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+        {
+            tracing::info!("not restoring stream {}, it expired", stream.id);
+            continue;
+        }
+        let id = stream.id.clone();
+        let restored = match validate_saved_stream(state, stream).await {
+            Ok(stream) => add_stream_to_state(state.clone(), stream).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = restored {
+            tracing::error!("could not restore stream {}: {:?}", id, err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Closes every RTSP session and client connection and releases the media of
+/// all streams. RTSP/1.0 has no server initiated TEARDOWN, so players are told
+/// the session is over by the server closing it.
+pub async fn drain_rtsp_clients(state: &AppState) {
+    let clients = state.health.rtsp_server.client_filter(None);
+    tracing::info!("closing {} rtsp clients", clients.len());
+    for client in clients {
+        client.session_filter(Some(&mut |_, _| RTSPFilterResult::Remove));
+        client.close();
+    }
+
+    for entry in state.streams.list() {
+        entry.lifecycle.stop();
+        for media in entry.medias.medias() {
+            if let Err(err) = media.unprepare() {
+                tracing::warn!("error while unpreparing media: {:?}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use axum::{
+    extract::{Path, State},
+    http, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    events::StreamEventType,
+    webhooks::{DeliveryRecord, Webhook},
+};
+
+use super::{
+    appstate::AppState,
+    error::{AppError, UserInputError},
+    source_validation::validate_webhook_url,
+};
+
+#[derive(Debug, Deserialize)]
+pub struct AddWebhookInput {
+    pub url: String,
+    #[serde(default)]
+    pub events: Vec<StreamEventType>,
+    pub secret: Option<String>,
+}
+
+pub async fn add_webhook(
+    State(state): State<AppState>,
+    Json(req): Json<AddWebhookInput>,
+) -> Result<Json<Webhook>, AppError> {
+    let url = validate_webhook_url(&req.url, &state.webhook_allowlist).await?;
+
+    let webhook = state
+        .webhooks
+        .register(url.to_string(), req.events, req.secret)
+        .ok_or_else(|| {
+            AppError::UserInputError(UserInputError {
+                status_code: http::StatusCode::BAD_REQUEST,
+                message: "a secret is required to sign webhook payloads".to_string(),
+                details: json!({
+                    "field": "secret",
+                    "hint": "pass a secret or configure WEBHOOK_SECRET",
+                }),
+            })
+        })?;
+    Ok(Json(webhook))
+}
+
+pub async fn list_webhooks(State(state): State<AppState>) -> Json<Vec<Webhook>> {
+    Json(state.webhooks.list())
+}
+
+pub async fn remove_webhook(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<String, AppError> {
+    if !state.webhooks.remove(&id) {
+        return Err(AppError::UserInputError(UserInputError {
+            status_code: http::StatusCode::NOT_FOUND,
+            message: "webhook not found".to_string(),
+            details: json!({ "id": id }),
+        }));
+    }
+    Ok("Webhook Removed".to_string())
+}
+
+pub async fn list_webhook_deliveries(State(state): State<AppState>) -> Json<Vec<DeliveryRecord>> {
+    Json(state.webhooks.deliveries())
+}
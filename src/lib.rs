@@ -2,4 +2,8 @@
 #![deny(clippy::expect_used)]
 pub mod http_server;
 pub mod rtsp_server;
-pub mod config;
\ No newline at end of file
+pub mod webhooks;
+pub mod config;
+pub mod events;
+pub mod metrics;
+pub mod net;
\ No newline at end of file
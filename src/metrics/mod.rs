@@ -0,0 +1,182 @@
+use prometheus::{
+    Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+/// Prometheus collectors for the relay. Per stream series are labelled with
+/// the stream id and dropped again when the stream is removed.
+pub struct RelayMetrics {
+    registry: Registry,
+    pub streams: IntGaugeVec,
+    pub stream_clients: IntGaugeVec,
+    pub relayed_bytes: IntCounterVec,
+    pub relayed_frames: IntCounterVec,
+    pub source_reconnects: IntCounterVec,
+    pub pipeline_errors: IntCounterVec,
+    pub source_switches: IntCounterVec,
+    pub stream_stalled: IntGaugeVec,
+    pub stream_frozen: IntGaugeVec,
+    pub stream_stalls: IntCounterVec,
+    pub stream_freezes: IntCounterVec,
+    pub timestamp_discontinuities: IntCounterVec,
+    pub stale_stream_removals: IntCounter,
+    pub transcoding_pipelines: IntGauge,
+    pub cpu_usage: Gauge,
+    pub http_request_duration: HistogramVec,
+}
+
+impl RelayMetrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("rtsp_relay".to_string()), None)?;
+
+        let streams = IntGaugeVec::new(
+            Opts::new("streams", "Number of mounted streams by kind"),
+            &["kind"],
+        )?;
+        let stream_clients = IntGaugeVec::new(
+            Opts::new("stream_clients", "Connected RTSP clients per stream"),
+            &["stream"],
+        )?;
+        let relayed_bytes = IntCounterVec::new(
+            Opts::new("relayed_bytes_total", "Bytes handed to the RTP payloader"),
+            &["stream"],
+        )?;
+        let relayed_frames = IntCounterVec::new(
+            Opts::new("relayed_frames_total", "Frames handed to the RTP payloader"),
+            &["stream"],
+        )?;
+        let source_reconnects = IntCounterVec::new(
+            Opts::new(
+                "source_reconnects_total",
+                "Times an upstream source was connected again after the first connection",
+            ),
+            &["stream"],
+        )?;
+        let pipeline_errors = IntCounterVec::new(
+            Opts::new("pipeline_errors_total", "Errors posted by stream pipelines"),
+            &["stream"],
+        )?;
+        let source_switches = IntCounterVec::new(
+            Opts::new(
+                "source_switches_total",
+                "Times a stream switched between its primary and backup sources",
+            ),
+            &["stream"],
+        )?;
+        let stream_stalled = IntGaugeVec::new(
+            Opts::new(
+                "stream_stalled",
+                "1 while a playing stream receives no frames from its source",
+            ),
+            &["stream"],
+        )?;
+        let stream_frozen = IntGaugeVec::new(
+            Opts::new(
+                "stream_frozen",
+                "1 while the picture of a stream does not change",
+            ),
+            &["stream"],
+        )?;
+        let stream_stalls = IntCounterVec::new(
+            Opts::new(
+                "stream_stalls_total",
+                "Times a playing stream stopped receiving frames",
+            ),
+            &["stream"],
+        )?;
+        let stream_freezes = IntCounterVec::new(
+            Opts::new(
+                "stream_freezes_total",
+                "Times the picture of a stream froze",
+            ),
+            &["stream"],
+        )?;
+        let timestamp_discontinuities = IntCounterVec::new(
+            Opts::new(
+                "timestamp_discontinuities_total",
+                "Frames whose timestamp went backwards or jumped ahead",
+            ),
+            &["stream"],
+        )?;
+        let stale_stream_removals = IntCounter::new(
+            "stale_stream_removals_total",
+            "Streams removed by the stale stream reaper",
+        )?;
+        let transcoding_pipelines = IntGauge::new(
+            "transcoding_pipelines",
+            "Transcoding pipelines currently running",
+        )?;
+        let cpu_usage = Gauge::new(
+            "cpu_usage_percent",
+            "CPU usage of the relay in percent of all cores",
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Latency of HTTP API requests",
+            ),
+            &["method", "path", "status"],
+        )?;
+
+        registry.register(Box::new(streams.clone()))?;
+        registry.register(Box::new(stream_clients.clone()))?;
+        registry.register(Box::new(relayed_bytes.clone()))?;
+        registry.register(Box::new(relayed_frames.clone()))?;
+        registry.register(Box::new(source_reconnects.clone()))?;
+        registry.register(Box::new(pipeline_errors.clone()))?;
+        registry.register(Box::new(source_switches.clone()))?;
+        registry.register(Box::new(stream_stalled.clone()))?;
+        registry.register(Box::new(stream_frozen.clone()))?;
+        registry.register(Box::new(stream_stalls.clone()))?;
+        registry.register(Box::new(stream_freezes.clone()))?;
+        registry.register(Box::new(timestamp_discontinuities.clone()))?;
+        registry.register(Box::new(stale_stream_removals.clone()))?;
+        registry.register(Box::new(transcoding_pipelines.clone()))?;
+        registry.register(Box::new(cpu_usage.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+
+        Ok(Self {
+            registry,
+            streams,
+            stream_clients,
+            relayed_bytes,
+            relayed_frames,
+            source_reconnects,
+            pipeline_errors,
+            source_switches,
+            stream_stalled,
+            stream_frozen,
+            stream_stalls,
+            stream_freezes,
+            timestamp_discontinuities,
+            stale_stream_removals,
+            transcoding_pipelines,
+            cpu_usage,
+            http_request_duration,
+        })
+    }
+
+    pub fn remove_stream(&self, stream_id: &str) {
+        let _ = self.stream_clients.remove_label_values(&[stream_id]);
+        let _ = self.relayed_bytes.remove_label_values(&[stream_id]);
+        let _ = self.relayed_frames.remove_label_values(&[stream_id]);
+        let _ = self.source_reconnects.remove_label_values(&[stream_id]);
+        let _ = self.pipeline_errors.remove_label_values(&[stream_id]);
+        let _ = self.source_switches.remove_label_values(&[stream_id]);
+        let _ = self.stream_stalled.remove_label_values(&[stream_id]);
+        let _ = self.stream_frozen.remove_label_values(&[stream_id]);
+        let _ = self.stream_stalls.remove_label_values(&[stream_id]);
+        let _ = self.stream_freezes.remove_label_values(&[stream_id]);
+        let _ = self
+            .timestamp_discontinuities
+            .remove_label_values(&[stream_id]);
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        prometheus::TEXT_FORMAT
+    }
+
+    pub fn render(&self) -> Result<String, prometheus::Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+}
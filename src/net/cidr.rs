@@ -0,0 +1,140 @@
+use std::net::IpAddr;
+
+#[derive(Debug, Clone)]
+pub struct CidrParseError {
+    pub reason: String,
+}
+
+/// An IPv4 or IPv6 network in CIDR notation. A bare address is treated as a
+/// single-host network (`/32` or `/128`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CidrBlock {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl CidrBlock {
+    pub fn parse(value: &str) -> Result<Self, CidrParseError> {
+        let value = value.trim();
+        let (address, prefix_len) = match value.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (value, None),
+        };
+
+        let network: IpAddr = address.parse().map_err(|_| CidrParseError {
+            reason: format!("'{}' is not a valid IP address", address),
+        })?;
+        let network = network.to_canonical();
+        let max_prefix_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>().map_err(|_| CidrParseError {
+                reason: format!("'{}' is not a valid prefix length", prefix_len),
+            })?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(CidrParseError {
+                reason: format!(
+                    "prefix length {} is out of range for {}",
+                    prefix_len, network
+                ),
+            });
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parses a comma separated list of CIDR blocks, ignoring empty entries.
+pub fn parse_cidr_list(value: &str) -> Result<Vec<CidrBlock>, CidrParseError> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(CidrBlock::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_addresses() {
+        let network = CidrBlock::parse(" 10.20.0.0/16 ").unwrap();
+        assert!(network.contains(&ip("10.20.255.1")));
+        assert!(!network.contains(&ip("10.21.0.1")));
+
+        let host = CidrBlock::parse("192.168.1.10").unwrap();
+        assert!(host.contains(&ip("192.168.1.10")));
+        assert!(!host.contains(&ip("192.168.1.11")));
+
+        let v6 = CidrBlock::parse("2001:db8::/32").unwrap();
+        assert!(v6.contains(&ip("2001:db8:1::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+        assert!(!v6.contains(&ip("10.20.0.1")));
+    }
+
+    #[test]
+    fn zero_prefix_matches_everything_of_its_family() {
+        let any = CidrBlock::parse("0.0.0.0/0").unwrap();
+        assert!(any.contains(&ip("203.0.113.7")));
+        assert!(!any.contains(&ip("::1")));
+    }
+
+    #[test]
+    fn mapped_addresses_are_treated_as_ipv4() {
+        let network = CidrBlock::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains(&ip("::ffff:10.1.2.3")));
+        assert_eq!(
+            CidrBlock::parse("::ffff:10.0.0.1").unwrap(),
+            CidrBlock::parse("10.0.0.1").unwrap()
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_blocks() {
+        assert!(CidrBlock::parse("10.0.0.0/33").is_err());
+        assert!(CidrBlock::parse("::/129").is_err());
+        assert!(CidrBlock::parse("10.0.0.0/x").is_err());
+        assert!(CidrBlock::parse("10.0.0").is_err());
+        assert!(CidrBlock::parse("camera.local").is_err());
+    }
+
+    #[test]
+    fn parses_lists() {
+        let networks = parse_cidr_list("10.0.0.0/8, ,192.168.0.1,").unwrap();
+        assert_eq!(networks.len(), 2);
+        assert!(parse_cidr_list("").unwrap().is_empty());
+        assert!(parse_cidr_list("10.0.0.0/8,nope").is_err());
+    }
+}
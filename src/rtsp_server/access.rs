@@ -0,0 +1,199 @@
+use std::{collections::HashMap, ffi::CStr, net::IpAddr, sync::RwLock};
+
+use glib::translate::ToGlibPtr;
+use gst_rtsp_server::RTSPContext;
+
+use crate::net::cidr::CidrBlock;
+
+/// Allow and deny lists for viewer addresses. Deny entries win; an empty
+/// allow list lets every address through that is not denied.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpPolicy {
+    pub allow: Vec<CidrBlock>,
+    pub deny: Vec<CidrBlock>,
+}
+
+impl ClientIpPolicy {
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    pub fn permits(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|network| network.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// Viewer IP policies shared between the HTTP API, which registers them per
+/// mount path, and the RTSP `Auth`, which enforces them.
+#[derive(Debug, Default)]
+pub struct ClientAccessControl {
+    global: ClientIpPolicy,
+    streams: RwLock<HashMap<String, ClientIpPolicy>>,
+}
+
+impl ClientAccessControl {
+    pub fn new(global: ClientIpPolicy) -> Self {
+        Self {
+            global,
+            streams: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn set_stream_policy(&self, path: &str, policy: ClientIpPolicy) {
+        let mut streams = self
+            .streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if policy.is_empty() {
+            streams.remove(path);
+        } else {
+            streams.insert(path.to_owned(), policy);
+        }
+    }
+
+    pub fn remove_stream_policy(&self, path: &str) {
+        self.streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(path);
+    }
+
+    /// Checks a viewer against the global policy and the policy of the mount
+    /// `request_path` belongs to. Unknown addresses are only let through when
+    /// no policy applies at all.
+    pub fn permits(&self, request_path: &str, ip: Option<IpAddr>) -> bool {
+        let mount_path = mount_path(request_path);
+        let streams = self
+            .streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let stream_policy = streams.get(&mount_path);
+
+        let Some(ip) = ip else {
+            return self.global.is_empty() && stream_policy.is_none();
+        };
+
+        self.global.permits(&ip) && stream_policy.is_none_or(|policy| policy.permits(&ip))
+    }
+}
+
+/// Streams are mounted at `/{id}`; SETUP requests address tracks below that,
+/// e.g. `/{id}/stream=0`, and any query is not part of the mount.
+pub fn mount_path(request_path: &str) -> String {
+    let id = request_path
+        .trim_start_matches('/')
+        .split(['/', '?'])
+        .next()
+        .unwrap_or_default();
+    format!("/{}", id)
+}
+
+/// Path of the URL a request was made for, empty when the request has none.
+pub fn request_path(ctx: &RTSPContext) -> String {
+    ctx.uri()
+        .and_then(|uri| url::Url::parse(&uri.request_uri()).ok())
+        .map(|uri| uri.path().to_owned())
+        .unwrap_or_default()
+}
+
+pub fn client_ip(client: &gst_rtsp_server::RTSPClient) -> Option<IpAddr> {
+    // SAFETY: the connection is owned by the client, which we hold a reference
+    // to for the whole call, and the returned string is owned by the
+    // connection. Both are only read here.
+    unsafe {
+        let connection =
+            gst_rtsp_server::ffi::gst_rtsp_client_get_connection(client.to_glib_none().0);
+        if connection.is_null() {
+            return None;
+        }
+        let ip = gst_rtsp::ffi::gst_rtsp_connection_get_ip(connection);
+        if ip.is_null() {
+            return None;
+        }
+        let ip = CStr::from_ptr(ip).to_str().ok()?;
+        // Link-local IPv6 addresses may carry a zone suffix such as `%eth0`.
+        let ip = ip.split('%').next().unwrap_or(ip);
+        ip.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::cidr::parse_cidr_list;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn policy(allow: &str, deny: &str) -> ClientIpPolicy {
+        ClientIpPolicy {
+            allow: parse_cidr_list(allow).unwrap(),
+            deny: parse_cidr_list(deny).unwrap(),
+        }
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let policy = policy("10.0.0.0/8", "10.1.0.0/16");
+        assert!(policy.permits(&ip("10.2.0.1")));
+        assert!(!policy.permits(&ip("10.1.0.1")));
+        assert!(!policy.permits(&ip("192.168.0.1")));
+    }
+
+    #[test]
+    fn empty_allow_list_permits_all_but_denied() {
+        let policy = policy("", "192.168.0.0/24");
+        assert!(policy.permits(&ip("203.0.113.7")));
+        assert!(policy.permits(&ip("::1")));
+        assert!(!policy.permits(&ip("192.168.0.9")));
+        assert!(ClientIpPolicy::default().permits(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn mapped_addresses_follow_the_ipv4_rules() {
+        let policy = policy("10.0.0.0/8", "10.1.0.0/16");
+        assert!(policy.permits(&ip("::ffff:10.2.0.1")));
+        assert!(!policy.permits(&ip("::ffff:10.1.0.1")));
+        assert!(!policy.permits(&ip("::ffff:192.168.0.1")));
+    }
+
+    #[test]
+    fn stream_policies_apply_on_top_of_the_global_one() {
+        let access = ClientAccessControl::new(policy("10.0.0.0/8", ""));
+        access.set_stream_policy("/cam1", policy("10.1.0.0/16", ""));
+
+        assert!(access.permits("/cam1/stream=0", Some(ip("10.1.0.1"))));
+        assert!(!access.permits("/cam1/stream=0", Some(ip("10.2.0.1"))));
+        assert!(access.permits("/cam2", Some(ip("10.2.0.1"))));
+        assert!(!access.permits("/cam2", Some(ip("192.168.0.1"))));
+
+        access.remove_stream_policy("/cam1");
+        assert!(access.permits("/cam1", Some(ip("10.2.0.1"))));
+    }
+
+    #[test]
+    fn unknown_addresses_pass_only_without_policies() {
+        let open = ClientAccessControl::new(ClientIpPolicy::default());
+        assert!(open.permits("/cam1", None));
+        open.set_stream_policy("/cam1", policy("", "10.0.0.0/8"));
+        assert!(!open.permits("/cam1", None));
+        assert!(open.permits("/cam2", None));
+
+        let restricted = ClientAccessControl::new(policy("10.0.0.0/8", ""));
+        assert!(!restricted.permits("/cam1", None));
+    }
+
+    #[test]
+    fn mount_paths_drop_tracks_and_queries() {
+        assert_eq!(mount_path("/cam1"), "/cam1");
+        assert_eq!(mount_path("/cam1/stream=0"), "/cam1");
+        assert_eq!(mount_path("/cam1?x"), "/cam1");
+        assert_eq!(mount_path("cam1/"), "/cam1");
+        assert_eq!(mount_path(""), "/");
+    }
+}
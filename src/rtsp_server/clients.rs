@@ -0,0 +1,404 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use chrono::Utc;
+use glib::translate::ToGlibPtr;
+use gst_rtsp::RTSPStatusCode;
+use gst_rtsp_server::{
+    prelude::*, RTSPClient, RTSPContext, RTSPFilterResult, RTSPServer, RTSPSessionMedia,
+};
+use ulid::Ulid;
+
+use crate::events::{EventBus, StreamEventType};
+
+use super::{
+    access::{client_ip, mount_path, request_path},
+    lifecycle::MediaLifecycle,
+};
+
+/// A viewer currently playing a stream.
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub stream_id: String,
+    pub ip: Option<String>,
+    pub user: Option<String>,
+    pub started_at: chrono::DateTime<Utc>,
+    pub transport: Option<String>,
+}
+
+struct TrackedClient {
+    info: ClientInfo,
+    client: glib::WeakRef<RTSPClient>,
+}
+
+/// Keeps track of which RTSP client is playing which stream. A client counts as
+/// a viewer from its first PLAY until it tears the session down or its
+/// connection closes.
+///
+/// Viewer limits are enforced on PLAY, which needs a session that SETUP only
+/// hands out once the client is authenticated, let in by the IP policy and the
+/// stream is mounted. A client asking to play holds a slot until it plays,
+/// tears down or disconnects, so clients racing through PLAY cannot overshoot
+/// the limit.
+#[derive(Default)]
+pub struct ClientTracker {
+    viewers: RwLock<HashMap<String, TrackedClient>>,
+    /// Streams clients asked to play but are not playing yet, keyed by client
+    /// id. Always locked after `viewers`.
+    reserved: Mutex<HashMap<String, String>>,
+    streams: RwLock<HashMap<String, TrackedStream>>,
+    global_limit: Option<usize>,
+    /// The account every viewer authenticates with, which tells no one apart.
+    shared_user: Option<String>,
+}
+
+struct TrackedStream {
+    max_clients: Option<usize>,
+    lifecycle: Arc<MediaLifecycle>,
+}
+
+impl ClientTracker {
+    pub fn new(global_limit: Option<usize>, shared_user: Option<String>) -> Self {
+        Self {
+            global_limit,
+            shared_user,
+            ..Default::default()
+        }
+    }
+
+    /// Whether `user` is the account all viewers share, so that disconnecting
+    /// it would drop everyone rather than one person.
+    pub fn is_shared_user(&self, user: &str) -> bool {
+        self.shared_user.as_deref() == Some(user)
+    }
+
+    /// Starts enforcing `max_clients` on a stream and reporting its first and
+    /// last viewers to `lifecycle`.
+    pub fn add_stream(
+        &self,
+        stream_id: &str,
+        max_clients: Option<usize>,
+        lifecycle: Arc<MediaLifecycle>,
+    ) {
+        self.streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(
+                stream_id.to_owned(),
+                TrackedStream {
+                    max_clients,
+                    lifecycle,
+                },
+            );
+    }
+
+    pub fn remove_stream(&self, stream_id: &str) {
+        self.streams
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .remove(stream_id);
+    }
+
+    fn lifecycle(&self, stream_id: &str) -> Option<Arc<MediaLifecycle>> {
+        self.streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(stream_id)
+            .map(|stream| stream.lifecycle.clone())
+    }
+
+    fn lock_reserved(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.reserved
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Holds a slot on `stream_id` for a client about to play it. Answers 503
+    /// when the relay as a whole is full and 453 when the stream is.
+    fn reserve(&self, client_id: &str, stream_id: &str) -> RTSPStatusCode {
+        let mut viewers = self
+            .viewers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        viewers.retain(|_, viewer| viewer.client.upgrade().is_some());
+        let mut reserved = self.lock_reserved();
+
+        let already_counted = viewers
+            .get(client_id)
+            .is_some_and(|viewer| viewer.info.stream_id == stream_id)
+            || reserved.get(client_id).map(String::as_str) == Some(stream_id);
+        if already_counted {
+            return RTSPStatusCode::Ok;
+        }
+
+        let in_use = viewers.len() + reserved.len();
+        if self.global_limit.is_some_and(|limit| in_use >= limit) {
+            tracing::warn!(
+                "refusing client {} on stream {}: relay is at its limit of {} clients",
+                client_id,
+                stream_id,
+                in_use
+            );
+            return RTSPStatusCode::ServiceUnavailable;
+        }
+
+        let stream_limit = self
+            .streams
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .get(stream_id)
+            .and_then(|stream| stream.max_clients);
+        let stream_in_use = count_viewers(&viewers, stream_id)
+            + reserved.values().filter(|id| *id == stream_id).count();
+        if stream_limit.is_some_and(|limit| stream_in_use >= limit) {
+            tracing::warn!(
+                "refusing client {} on stream {}: stream is at its limit of {} clients",
+                client_id,
+                stream_id,
+                stream_in_use
+            );
+            return RTSPStatusCode::NotEnoughBandwidth;
+        }
+
+        reserved.insert(client_id.to_owned(), stream_id.to_owned());
+        RTSPStatusCode::Ok
+    }
+
+    /// Hooks the client signals of `server` and publishes a client connected
+    /// or disconnected event whenever a viewer starts or stops watching.
+    pub fn attach(self: &Arc<Self>, server: &RTSPServer, events: EventBus) {
+        let tracker = self.clone();
+        server.connect_client_connected(move |_, client| {
+            let client_id = Ulid::new().to_string();
+            let ip = client_ip(client).map(|ip| ip.to_string());
+
+            // Not on SETUP: pre-setup-request is emitted before the client is
+            // authenticated and the mount is looked up.
+            let reserve_tracker = tracker.clone();
+            let reserve_client_id = client_id.clone();
+            client.connect_pre_play_request(move |_, ctx| {
+                reserve_tracker.reserve(&reserve_client_id, &request_stream_id(ctx))
+            });
+
+            let play_tracker = tracker.clone();
+            let play_events = events.clone();
+            let play_client_id = client_id.clone();
+            client.connect_play_request(move |client, ctx| {
+                let info = ClientInfo {
+                    client_id: play_client_id.clone(),
+                    stream_id: request_stream_id(ctx),
+                    ip: ip.clone(),
+                    user: ctx
+                        .token()
+                        .and_then(|token| token.string("user"))
+                        .map(|user| user.to_string()),
+                    started_at: Utc::now(),
+                    transport: ctx.session_media().and_then(lower_transport),
+                };
+                if let Some(info) = play_tracker.start_viewing(info, client) {
+                    play_events.publish(
+                        StreamEventType::ClientConnected,
+                        &info.stream_id,
+                        Some(client_event_details(&info)),
+                    );
+                }
+            });
+
+            let teardown_tracker = tracker.clone();
+            let teardown_events = events.clone();
+            let teardown_client_id = client_id.clone();
+            client.connect_teardown_request(move |_, _| {
+                teardown_tracker.stop_viewing(&teardown_client_id, &teardown_events);
+            });
+
+            let closed_tracker = tracker.clone();
+            let closed_events = events.clone();
+            client.connect_closed(move |_| {
+                closed_tracker.stop_viewing(&client_id, &closed_events);
+            });
+        });
+    }
+
+    /// Records `info` as a viewer. PLAY is sent again when resuming after a
+    /// PAUSE, so a client already watching the same stream is not recorded
+    /// twice and `None` is returned.
+    fn start_viewing(&self, info: ClientInfo, client: &RTSPClient) -> Option<ClientInfo> {
+        let mut viewers = self
+            .viewers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if viewers
+            .get(&info.client_id)
+            .is_some_and(|viewer| viewer.info.stream_id == info.stream_id)
+        {
+            return None;
+        }
+        self.lock_reserved().remove(&info.client_id);
+        let previous = viewers.insert(
+            info.client_id.clone(),
+            TrackedClient {
+                info: info.clone(),
+                client: client.downgrade(),
+            },
+        );
+        let first_viewer = count_viewers(&viewers, &info.stream_id) == 1;
+        // The client switched streams, the one it left may have no viewers now.
+        let left_stream = previous
+            .map(|previous| previous.info.stream_id)
+            .filter(|stream_id| count_viewers(&viewers, stream_id) == 0);
+        drop(viewers);
+
+        if first_viewer {
+            if let Some(lifecycle) = self.lifecycle(&info.stream_id) {
+                lifecycle.viewer_joined();
+            }
+        }
+        if let Some(lifecycle) = left_stream.and_then(|stream_id| self.lifecycle(&stream_id)) {
+            lifecycle.viewers_left();
+        }
+
+        Some(info)
+    }
+
+    fn stop_viewing(&self, client_id: &str, events: &EventBus) {
+        let mut viewers = self
+            .viewers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.lock_reserved().remove(client_id);
+        let viewer = viewers.remove(client_id);
+        let last_viewer = viewer
+            .as_ref()
+            .is_some_and(|viewer| count_viewers(&viewers, &viewer.info.stream_id) == 0);
+        drop(viewers);
+        if let Some(viewer) = viewer {
+            if last_viewer {
+                if let Some(lifecycle) = self.lifecycle(&viewer.info.stream_id) {
+                    lifecycle.viewers_left();
+                }
+            }
+            events.publish(
+                StreamEventType::ClientDisconnected,
+                &viewer.info.stream_id,
+                Some(client_event_details(&viewer.info)),
+            );
+        }
+    }
+
+    /// Viewers of `stream_id`, dropping the ones whose client is gone.
+    pub fn viewers(&self, stream_id: &str) -> Vec<ClientInfo> {
+        let mut viewers = self
+            .viewers
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        viewers.retain(|_, viewer| viewer.client.upgrade().is_some());
+
+        let mut stream_viewers = viewers
+            .values()
+            .filter(|viewer| viewer.info.stream_id == stream_id)
+            .map(|viewer| viewer.info.clone())
+            .collect::<Vec<ClientInfo>>();
+        stream_viewers.sort_by_key(|viewer| viewer.started_at);
+        stream_viewers
+    }
+
+    pub fn viewer_count(&self, stream_id: &str) -> usize {
+        self.viewers(stream_id).len()
+    }
+
+    /// Closes the sessions and the connection of the viewers `predicate`
+    /// selects. The viewers are dropped from the tracker by the `closed`
+    /// handler, so the lock is released before closing anything.
+    fn disconnect_where(&self, predicate: impl Fn(&ClientInfo) -> bool) -> Vec<ClientInfo> {
+        let selected = self
+            .viewers
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .values()
+            .filter(|viewer| predicate(&viewer.info))
+            .filter_map(|viewer| Some((viewer.info.clone(), viewer.client.upgrade()?)))
+            .collect::<Vec<(ClientInfo, RTSPClient)>>();
+
+        selected
+            .into_iter()
+            .map(|(info, client)| {
+                tracing::info!(
+                    "disconnecting client {} from stream {}",
+                    info.client_id,
+                    info.stream_id
+                );
+                client.session_filter(Some(&mut |_, _| RTSPFilterResult::Remove));
+                client.close();
+                info
+            })
+            .collect()
+    }
+
+    /// Disconnects one viewer of `stream_id`. Returns `None` when the client
+    /// is not watching that stream.
+    pub fn disconnect(&self, stream_id: &str, client_id: &str) -> Option<ClientInfo> {
+        self.disconnect_where(|info| info.stream_id == stream_id && info.client_id == client_id)
+            .pop()
+    }
+
+    /// Disconnects every viewer authenticated as `user`, on `stream_id` or on
+    /// all streams. Nothing keeps them from connecting again while the account
+    /// is still valid.
+    pub fn disconnect_user(&self, user: &str, stream_id: Option<&str>) -> Vec<ClientInfo> {
+        self.disconnect_where(|info| {
+            info.user.as_deref() == Some(user)
+                && stream_id.is_none_or(|stream_id| info.stream_id == stream_id)
+        })
+    }
+}
+
+fn count_viewers(viewers: &HashMap<String, TrackedClient>, stream_id: &str) -> usize {
+    viewers
+        .values()
+        .filter(|viewer| viewer.info.stream_id == stream_id)
+        .count()
+}
+
+fn client_event_details(info: &ClientInfo) -> serde_json::Value {
+    serde_json::json!({
+        "client_id": info.client_id,
+        "client_ip": info.ip,
+        "user": info.user,
+    })
+}
+
+/// Lower transport the client negotiated for the first track of the session.
+fn lower_transport(session_media: &RTSPSessionMedia) -> Option<String> {
+    let stream_transport = session_media.transport(0)?;
+    // SAFETY: the transport is owned by the stream transport, which we hold a
+    // reference to for the whole call, and it is only read here.
+    let lower_transport = unsafe {
+        let transport = gst_rtsp_server::ffi::gst_rtsp_stream_transport_get_transport(
+            stream_transport.to_glib_none().0,
+        );
+        if transport.is_null() {
+            return None;
+        }
+        (*transport).lower_transport
+    };
+
+    // TLS is a flag on top of the actual transport.
+    let name = match lower_transport & !gst_rtsp::ffi::GST_RTSP_LOWER_TRANS_TLS {
+        gst_rtsp::ffi::GST_RTSP_LOWER_TRANS_TCP => "tcp",
+        gst_rtsp::ffi::GST_RTSP_LOWER_TRANS_UDP_MCAST => "udp-multicast",
+        gst_rtsp::ffi::GST_RTSP_LOWER_TRANS_UDP => "udp",
+        gst_rtsp::ffi::GST_RTSP_LOWER_TRANS_HTTP => "http",
+        _ => return None,
+    };
+    Some(name.to_owned())
+}
+
+/// The stream a request addresses, e.g. `cam1` for `/cam1/stream=0`.
+pub fn request_stream_id(ctx: &RTSPContext) -> String {
+    mount_path(&request_path(ctx))
+        .trim_start_matches('/')
+        .to_owned()
+}
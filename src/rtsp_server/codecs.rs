@@ -0,0 +1,278 @@
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+use super::factory::PipelineBuildError;
+
+/// H.264 encoders the relay knows how to configure, hardware ones first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264Encoder {
+    Nvenc,
+    Va,
+    Vaapi,
+    V4l2,
+    X264,
+    OpenH264,
+}
+
+/// H.264 decoders the relay can use, hardware ones first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum H264Decoder {
+    Nvdec,
+    Va,
+    Vaapi,
+    V4l2,
+    Avdec,
+    OpenH264,
+}
+
+impl H264Encoder {
+    const ALL: [H264Encoder; 6] = [
+        H264Encoder::Nvenc,
+        H264Encoder::Va,
+        H264Encoder::Vaapi,
+        H264Encoder::V4l2,
+        H264Encoder::X264,
+        H264Encoder::OpenH264,
+    ];
+    const SOFTWARE: [H264Encoder; 2] = [H264Encoder::X264, H264Encoder::OpenH264];
+
+    pub fn element_name(self) -> &'static str {
+        match self {
+            H264Encoder::Nvenc => "nvh264enc",
+            H264Encoder::Va => "vah264enc",
+            H264Encoder::Vaapi => "vaapih264enc",
+            H264Encoder::V4l2 => "v4l2h264enc",
+            H264Encoder::X264 => "x264enc",
+            H264Encoder::OpenH264 => "openh264enc",
+        }
+    }
+
+    /// Builds the encoder for low latency streaming at `bitrate` kbit/s with
+    /// a keyframe every `keyframe_interval` frames.
+    fn build(self, bitrate: u32, keyframe_interval: u32) -> Result<gst::Element, glib::BoolError> {
+        let encoder = gst::ElementFactory::make(self.element_name()).build()?;
+        match self {
+            H264Encoder::X264 => {
+                set_if_present(&encoder, "tune", "zerolatency");
+                set_if_present(&encoder, "speed-preset", "ultrafast");
+                set_if_present(&encoder, "bitrate", &bitrate.to_string());
+                set_if_present(&encoder, "key-int-max", &keyframe_interval.to_string());
+            }
+            H264Encoder::OpenH264 => {
+                set_if_present(&encoder, "bitrate", &(bitrate * 1000).to_string());
+                set_if_present(&encoder, "gop-size", &keyframe_interval.to_string());
+            }
+            H264Encoder::Nvenc => {
+                set_if_present(&encoder, "zerolatency", "true");
+                set_if_present(&encoder, "bitrate", &bitrate.to_string());
+                set_if_present(&encoder, "gop-size", &keyframe_interval.to_string());
+            }
+            H264Encoder::Va => {
+                set_if_present(&encoder, "bitrate", &bitrate.to_string());
+                set_if_present(&encoder, "key-int-max", &keyframe_interval.to_string());
+            }
+            H264Encoder::Vaapi => {
+                set_if_present(&encoder, "bitrate", &bitrate.to_string());
+                set_if_present(&encoder, "keyframe-period", &keyframe_interval.to_string());
+            }
+            H264Encoder::V4l2 => {
+                if encoder.find_property("extra-controls").is_some() {
+                    let controls = gst::Structure::builder("controls")
+                        .field("video_bitrate", (bitrate * 1000) as i32)
+                        .field("h264_i_frame_period", keyframe_interval as i32)
+                        .build();
+                    encoder.set_property("extra-controls", controls);
+                }
+            }
+        }
+        Ok(encoder)
+    }
+}
+
+impl H264Decoder {
+    const ALL: [H264Decoder; 6] = [
+        H264Decoder::Nvdec,
+        H264Decoder::Va,
+        H264Decoder::Vaapi,
+        H264Decoder::V4l2,
+        H264Decoder::Avdec,
+        H264Decoder::OpenH264,
+    ];
+    const SOFTWARE: [H264Decoder; 2] = [H264Decoder::Avdec, H264Decoder::OpenH264];
+
+    pub fn element_name(self) -> &'static str {
+        match self {
+            H264Decoder::Nvdec => "nvh264dec",
+            H264Decoder::Va => "vah264dec",
+            H264Decoder::Vaapi => "vaapih264dec",
+            H264Decoder::V4l2 => "v4l2h264dec",
+            H264Decoder::Avdec => "avdec_h264",
+            H264Decoder::OpenH264 => "openh264dec",
+        }
+    }
+
+    fn build(self) -> Result<gst::Element, glib::BoolError> {
+        gst::ElementFactory::make(self.element_name()).build()
+    }
+}
+
+/// Sets a property from its string form, when the element has it. Versions
+/// and vendors of the hardware plugins do not all expose the same ones.
+fn set_if_present(element: &gst::Element, name: &str, value: &str) {
+    if element.find_property(name).is_some() {
+        element.set_property_from_str(name, value);
+    }
+}
+
+#[derive(Debug)]
+pub struct CodecConfigError {
+    pub reason: String,
+}
+
+/// Encoder and decoder asked for in the configuration, `None` meaning the
+/// first one available.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CodecConfig {
+    pub encoder: Option<H264Encoder>,
+    pub decoder: Option<H264Decoder>,
+}
+
+impl CodecConfig {
+    /// Parses element names, "auto" or nothing picking one automatically.
+    pub fn parse(encoder: &str, decoder: &str) -> Result<Self, CodecConfigError> {
+        let encoder = match encoder {
+            "" | "auto" => None,
+            name => Some(
+                H264Encoder::ALL
+                    .into_iter()
+                    .find(|encoder| encoder.element_name() == name)
+                    .ok_or_else(|| CodecConfigError {
+                        reason: format!("unknown H.264 encoder {}", name),
+                    })?,
+            ),
+        };
+        let decoder = match decoder {
+            "" | "auto" => None,
+            name => Some(
+                H264Decoder::ALL
+                    .into_iter()
+                    .find(|decoder| decoder.element_name() == name)
+                    .ok_or_else(|| CodecConfigError {
+                        reason: format!("unknown H.264 decoder {}", name),
+                    })?,
+            ),
+        };
+        Ok(Self { encoder, decoder })
+    }
+}
+
+/// Whether `element_name` is registered and its element can be opened,
+/// which for hardware elements means the device is there.
+fn is_usable(element_name: &str) -> bool {
+    if gst::ElementFactory::find(element_name).is_none() {
+        return false;
+    }
+    let Ok(element) = gst::ElementFactory::make(element_name).build() else {
+        return false;
+    };
+    let usable = element.set_state(gst::State::Ready).is_ok();
+    let _ = element.set_state(gst::State::Null);
+    usable
+}
+
+/// The encoder and decoder transcoded streams use.
+#[derive(Debug, Clone, Copy)]
+pub struct Codecs {
+    pub encoder: H264Encoder,
+    pub decoder: H264Decoder,
+}
+
+impl Codecs {
+    /// Probes the GStreamer registry for the configured elements, or for
+    /// the first usable ones, falling back to software. GStreamer has to be
+    /// initialized. Passthrough streams need neither, so nothing being
+    /// usable is only worth a warning.
+    pub fn probe(config: CodecConfig) -> Self {
+        let encoders = match config.encoder {
+            Some(encoder) => [&[encoder][..], &H264Encoder::SOFTWARE].concat(),
+            None => H264Encoder::ALL.to_vec(),
+        };
+        let encoder = encoders
+            .into_iter()
+            .find(|encoder| is_usable(encoder.element_name()))
+            .unwrap_or_else(|| {
+                tracing::warn!("no usable H.264 encoder found, transcoding will fail");
+                H264Encoder::X264
+            });
+        let decoders = match config.decoder {
+            Some(decoder) => [&[decoder][..], &H264Decoder::SOFTWARE].concat(),
+            None => H264Decoder::ALL.to_vec(),
+        };
+        let decoder = decoders
+            .into_iter()
+            .find(|decoder| is_usable(decoder.element_name()))
+            .unwrap_or_else(|| {
+                tracing::warn!("no usable H.264 decoder found, transcoding will fail");
+                H264Decoder::Avdec
+            });
+
+        for (wanted, used) in [
+            (
+                config.encoder.map(H264Encoder::element_name),
+                encoder.element_name(),
+            ),
+            (
+                config.decoder.map(H264Decoder::element_name),
+                decoder.element_name(),
+            ),
+        ] {
+            if let Some(wanted) = wanted.filter(|wanted| *wanted != used) {
+                tracing::warn!("{} is not usable, falling back to {}", wanted, used);
+            }
+        }
+        tracing::info!(
+            "transcoding with {} and {}",
+            decoder.element_name(),
+            encoder.element_name()
+        );
+        Self { encoder, decoder }
+    }
+
+    /// Builds the selected encoder, or a software one when that fails.
+    pub(super) fn encoder(
+        &self,
+        bitrate: u32,
+        keyframe_interval: u32,
+    ) -> Result<gst::Element, PipelineBuildError> {
+        let mut last_error = None;
+        for encoder in std::iter::once(self.encoder).chain(H264Encoder::SOFTWARE) {
+            match encoder.build(bitrate, keyframe_interval) {
+                Ok(element) => return Ok(element),
+                Err(err) => {
+                    tracing::warn!("could not create {}: {}", encoder.element_name(), err);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(PipelineBuildError {
+            reason: format!("no H.264 encoder could be created: {:?}", last_error),
+        })
+    }
+
+    /// Builds the selected decoder, or a software one when that fails.
+    pub(super) fn decoder(&self) -> Result<gst::Element, PipelineBuildError> {
+        let mut last_error = None;
+        for decoder in std::iter::once(self.decoder).chain(H264Decoder::SOFTWARE) {
+            match decoder.build() {
+                Ok(element) => return Ok(element),
+                Err(err) => {
+                    tracing::warn!("could not create {}: {}", decoder.element_name(), err);
+                    last_error = Some(err);
+                }
+            }
+        }
+        Err(PipelineBuildError {
+            reason: format!("no H.264 decoder could be created: {:?}", last_error),
+        })
+    }
+}
@@ -0,0 +1,378 @@
+use std::{sync::Arc, time::Duration};
+
+use gst_rtsp_server::subclass::prelude::*;
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+use super::{
+    codecs::Codecs,
+    failover::build_failover_source,
+    governor::TranscodeGovernor,
+    masks::{attach_masks, PrivacyMasks},
+    overlay::{overlay_elements, OverlaySpec},
+    runtime::{SourceMediaInfo, StreamRuntime},
+    slate::SlateConfig,
+    transform::{transform_elements, VideoTransform},
+    watchdog::StreamWatchdog,
+};
+
+/// Size transcoded streams are scaled to, upright after `transform`.
+pub(super) fn down_scaled_size(transform: &VideoTransform) -> (i32, i32) {
+    if transform.transposes() {
+        (320, 640)
+    } else {
+        (640, 320)
+    }
+}
+
+/// Everything needed to build the relay pipeline of a single stream.
+#[derive(Debug, Clone)]
+pub struct PipelineSpec {
+    pub source_url: String,
+    /// Sources to fall over to, in order, when the primary fails or stalls.
+    pub backup_source_urls: Vec<String>,
+    /// How long a source may go without delivering before it counts as
+    /// stalled.
+    pub stall_timeout: Duration,
+    /// Shown to viewers while no source delivers.
+    pub slate: Option<SlateConfig>,
+    pub down_scale: bool,
+    /// Encoder and decoder used when transcoding.
+    pub codecs: Codecs,
+    /// Counts the pipeline while it transcodes.
+    pub governor: Arc<TranscodeGovernor>,
+    /// Applied to the picture of transcoded streams before scaling.
+    pub transform: VideoTransform,
+    /// Frame rate transcoded streams are limited to.
+    pub max_framerate: Option<u32>,
+    /// Relays only the keyframes of passthrough streams.
+    pub keyframes_only: bool,
+    /// Drawn on the picture of transcoded streams.
+    pub overlays: Vec<OverlaySpec>,
+    /// Hidden on the picture of transcoded streams.
+    pub masks: PrivacyMasks,
+}
+
+#[derive(Debug)]
+pub struct PipelineBuildError {
+    pub reason: String,
+}
+
+impl From<glib::BoolError> for PipelineBuildError {
+    fn from(err: glib::BoolError) -> Self {
+        PipelineBuildError {
+            reason: err.to_string(),
+        }
+    }
+}
+
+fn source_media_info(caps: &gst::CapsRef) -> SourceMediaInfo {
+    let Some(structure) = caps.structure(0) else {
+        return SourceMediaInfo::default();
+    };
+    let codec = match structure.name().as_str() {
+        "video/x-h264" => "H.264".to_owned(),
+        "video/x-h265" => "H.265".to_owned(),
+        other => other.to_owned(),
+    };
+
+    SourceMediaInfo {
+        codec: Some(codec),
+        width: structure.get::<i32>("width").ok(),
+        height: structure.get::<i32>("height").ok(),
+        framerate: structure
+            .get::<gst::Fraction>("framerate")
+            .ok()
+            .filter(|framerate| framerate.numer() > 0)
+            .map(|framerate| format!("{}/{}", framerate.numer(), framerate.denom())),
+    }
+}
+
+/// Adds `rtspsrc ! rtph264depay ! h264parse` for `url` to `bin` and returns
+/// the parser. `on_connected` runs whenever the source exposes its video pad.
+pub(super) fn source_elements(
+    bin: &gst::Bin,
+    url: &str,
+    down_scale: bool,
+    config_interval: Option<i32>,
+    on_connected: impl Fn() + Send + Sync + 'static,
+) -> Result<gst::Element, PipelineBuildError> {
+    let src = if down_scale {
+        gst::ElementFactory::make("rtspsrc")
+            .property("location", url)
+            .property("latency", 0u32)
+            .build()?
+    } else {
+        gst::ElementFactory::make("rtspsrc")
+            .property("location", url)
+            .property("latency", 50u32)
+            .property_from_str("protocols", "tcp")
+            .build()?
+    };
+    let depay = gst::ElementFactory::make("rtph264depay").build()?;
+    let parse = match config_interval {
+        Some(config_interval) => gst::ElementFactory::make("h264parse")
+            .property("config-interval", config_interval)
+            .build()?,
+        None => gst::ElementFactory::make("h264parse").build()?,
+    };
+
+    bin.add_many([&src, &depay, &parse])?;
+    gst::Element::link_many([&depay, &parse])?;
+
+    let depay_weak = depay.downgrade();
+    src.connect_pad_added(move |_, src_pad| {
+        let Some(depay) = depay_weak.upgrade() else {
+            return;
+        };
+        let Some(sink_pad) = depay.static_pad("sink") else {
+            return;
+        };
+        if sink_pad.is_linked() {
+            return;
+        }
+
+        let media = src_pad.current_caps().and_then(|caps| {
+            caps.structure(0)
+                .and_then(|s| s.get::<String>("media").ok())
+        });
+        if media.as_deref() != Some("video") {
+            return;
+        }
+
+        match src_pad.link(&sink_pad) {
+            Ok(_) => on_connected(),
+            Err(err) => {
+                tracing::warn!("could not link source pad {}: {:?}", src_pad.name(), err)
+            }
+        }
+    });
+
+    Ok(parse)
+}
+
+/// Builds the relay bin element by element instead of going through
+/// `gst_parse_launch`, so the source URL only ever ends up in the `location`
+/// property of `rtspsrc` and can not inject additional elements.
+pub fn build_pipeline(
+    spec: &PipelineSpec,
+    runtime: &Arc<StreamRuntime>,
+) -> Result<gst::Bin, PipelineBuildError> {
+    let bin = gst::Bin::new();
+
+    let source = if spec.backup_source_urls.is_empty() && spec.slate.is_none() {
+        let source_runtime = runtime.clone();
+        source_elements(
+            &bin,
+            &spec.source_url,
+            spec.down_scale,
+            (!spec.down_scale).then_some(1),
+            move || source_runtime.record_source_connected(),
+        )?
+    } else {
+        build_failover_source(&bin, spec, runtime)?
+    };
+
+    if let Some(source_src) = source.static_pad("src") {
+        let runtime = runtime.clone();
+        source_src.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+            if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+                if let gst::EventView::Caps(caps) = event.view() {
+                    runtime.record_media_info(source_media_info(caps.caps()));
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    let mut chain = vec![];
+    if spec.down_scale {
+        let (width, height) = down_scaled_size(&spec.transform);
+        let caps = gst::Caps::builder("video/x-raw")
+            .field("width", width)
+            .field("height", height)
+            .field("format", "I420")
+            .build();
+        let capsfilter = gst::ElementFactory::make("capsfilter")
+            .property("caps", &caps)
+            .build()?;
+        // Masks go first so that nothing drawn later is hidden.
+        if let Some(capsfilter_src) = capsfilter.static_pad("src") {
+            attach_masks(&capsfilter_src, spec.masks.clone());
+        }
+        chain.push(spec.codecs.decoder()?);
+        // Hardware decoders hand out other formats than I420.
+        chain.push(gst::ElementFactory::make("videoconvert").build()?);
+        chain.extend(transform_elements(&spec.transform)?);
+        if let Some(max_framerate) = spec.max_framerate {
+            chain.push(
+                gst::ElementFactory::make("videorate")
+                    .property("max-rate", i32::try_from(max_framerate).unwrap_or(i32::MAX))
+                    .property("drop-only", true)
+                    .build()?,
+            );
+        }
+        chain.push(gst::ElementFactory::make("videoscale").build()?);
+        chain.push(capsfilter);
+        chain.extend(overlay_elements(&spec.overlays)?);
+        // And hardware encoders may not take I420.
+        chain.push(gst::ElementFactory::make("videoconvert").build()?);
+        chain.push(spec.codecs.encoder(500, 30)?);
+        chain.push(gst::ElementFactory::make("h264parse").build()?);
+    }
+    let pay = gst::ElementFactory::make("rtph264pay")
+        .name("pay0")
+        .property("pt", 96u32)
+        .property("config-interval", 1i32)
+        .build()?;
+    chain.push(pay.clone());
+
+    bin.add_many(&chain)?;
+    chain.insert(0, source);
+    gst::Element::link_many(&chain)?;
+
+    if let Some(pay_sink) = pay.static_pad("sink") {
+        let runtime = runtime.clone();
+        let watchdog = StreamWatchdog::start(
+            &pay,
+            spec.stall_timeout,
+            spec.keyframes_only,
+            runtime.clone(),
+        );
+        let keyframes_only = spec.keyframes_only;
+        pay_sink.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                // Keyframes carry their parameter sets and decode on their
+                // own, the frames in between are not needed.
+                if keyframes_only && buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                    return gst::PadProbeReturn::Drop;
+                }
+                runtime.record_frame(buffer.size());
+                watchdog.frame(buffer);
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    Ok(bin)
+}
+
+mod imp {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    };
+
+    use gst_rtsp_server::{prelude::*, subclass::prelude::*, RTSPMedia};
+    use gstreamer as gst;
+    use gstreamer::prelude::*;
+
+    use super::{build_pipeline, PipelineSpec};
+    use crate::rtsp_server::runtime::StreamRuntime;
+
+    #[derive(Default)]
+    pub struct RelayMediaFactory {
+        pub(super) spec: Mutex<Option<PipelineSpec>>,
+        pub(super) runtime: OnceLock<Arc<StreamRuntime>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for RelayMediaFactory {
+        const NAME: &'static str = "RsRelayMediaFactory";
+        type Type = super::RelayMediaFactory;
+        type ParentType = gst_rtsp_server::RTSPMediaFactory;
+    }
+
+    impl ObjectImpl for RelayMediaFactory {}
+
+    impl RTSPMediaFactoryImpl for RelayMediaFactory {
+        // Every viewer of the mount shares one media, whatever host or query
+        // the URL they used carries. This also lets a media brought up before
+        // any viewer, e.g. for always-on streams, be found again.
+        fn gen_key(&self, _url: &gst_rtsp::RTSPUrl) -> Option<glib::GString> {
+            Some("relay".into())
+        }
+
+        fn create_element(&self, _url: &gst_rtsp::RTSPUrl) -> Option<gst::Element> {
+            let spec = self
+                .spec
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .clone()?;
+            let runtime = self.runtime.get()?;
+
+            match build_pipeline(&spec, runtime) {
+                Ok(bin) => Some(bin.upcast()),
+                Err(err) => {
+                    tracing::error!("failed to build relay pipeline: {}", err.reason);
+                    None
+                }
+            }
+        }
+
+        fn configure(&self, media: &RTSPMedia) {
+            self.parent_configure(media);
+
+            let Some(runtime) = self.runtime.get().cloned() else {
+                return;
+            };
+            let transcoding_governor = self
+                .spec
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .as_ref()
+                .filter(|spec| spec.down_scale)
+                .map(|spec| spec.governor.clone());
+            // A media that failed to prepare is unprepared all the same, so
+            // only the ones that were prepared are counted down again.
+            let counted = Arc::new(AtomicBool::new(false));
+            if let Some(governor) = transcoding_governor.clone() {
+                let counted = counted.clone();
+                media.connect_prepared(move |_| {
+                    if !counted.swap(true, Ordering::Relaxed) {
+                        governor.pipeline_started();
+                    }
+                });
+            }
+            let unprepared_runtime = runtime.clone();
+            media.connect_unprepared(move |_| {
+                unprepared_runtime.record_source_disconnected();
+                if let Some(governor) = &transcoding_governor {
+                    if counted.swap(false, Ordering::Relaxed) {
+                        governor.pipeline_stopped();
+                    }
+                }
+            });
+            media.connect("handle-message", false, move |args| {
+                let message = args.get(1).and_then(|value| value.get::<gst::Message>().ok());
+                if let Some(message) = message {
+                    match message.view() {
+                        gst::MessageView::Error(err) => {
+                            runtime.record_pipeline_error(&err.error().to_string())
+                        }
+                        gst::MessageView::Eos(_) => runtime.record_source_disconnected(),
+                        _ => {}
+                    }
+                }
+                Some(true.to_value())
+            });
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct RelayMediaFactory(ObjectSubclass<imp::RelayMediaFactory>) @extends gst_rtsp_server::RTSPMediaFactory;
+}
+
+impl RelayMediaFactory {
+    pub fn new(spec: PipelineSpec, runtime: Arc<StreamRuntime>) -> Self {
+        let factory: Self = glib::Object::new();
+        *factory
+            .imp()
+            .spec
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(spec);
+        let _ = factory.imp().runtime.set(runtime);
+        factory
+    }
+}
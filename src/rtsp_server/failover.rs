@@ -0,0 +1,488 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use gstreamer as gst;
+use gstreamer::{prelude::*, subclass::prelude::*};
+
+use super::{
+    factory::{down_scaled_size, source_elements, PipelineBuildError, PipelineSpec},
+    governor::TranscodeGovernor,
+    runtime::StreamRuntime,
+    slate::{set_slate_running, slate_elements},
+};
+
+const MONITOR_INTERVAL: Duration = Duration::from_secs(1);
+/// A source that delivered nothing for this many stall timeouts is restarted.
+const RESTART_AFTER_STALLS: u32 = 3;
+/// Size of the slate when the format of the sources is not known yet.
+const DEFAULT_SLATE_SIZE: (i32, i32) = (1280, 720);
+
+struct SourceStatus {
+    started_at: Instant,
+    last_buffer: Option<Instant>,
+    healthy_since: Option<Instant>,
+    failed: bool,
+}
+
+/// Health of one of the sources of a stream, fed by the buffers reaching the
+/// input selector and by the errors its bin swallows.
+pub struct SourceState {
+    index: usize,
+    runtime: Arc<StreamRuntime>,
+    status: Mutex<SourceStatus>,
+}
+
+impl SourceState {
+    fn new(index: usize, runtime: Arc<StreamRuntime>) -> Self {
+        Self {
+            index,
+            runtime,
+            status: Mutex::new(SourceStatus {
+                started_at: Instant::now(),
+                last_buffer: None,
+                healthy_since: None,
+                failed: false,
+            }),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SourceStatus> {
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record_error(&self, message: &str) {
+        self.lock().failed = true;
+        self.runtime.record_source_error(self.index, message);
+    }
+
+    fn record_buffer(&self) {
+        let now = Instant::now();
+        let mut status = self.lock();
+        status.last_buffer = Some(now);
+        status.healthy_since.get_or_insert(now);
+    }
+
+    fn reset(&self) {
+        let mut status = self.lock();
+        status.started_at = Instant::now();
+        status.last_buffer = None;
+        status.healthy_since = None;
+        status.failed = false;
+    }
+}
+
+mod imp {
+    use std::sync::{Arc, OnceLock};
+
+    use gstreamer as gst;
+    use gstreamer::subclass::prelude::*;
+
+    /// Bin around the elements of one source. Errors of its children stop
+    /// here instead of reaching the media, which would tear the whole stream
+    /// down while the other sources are fine.
+    #[derive(Default)]
+    pub struct SourceBin {
+        pub(super) source: OnceLock<Arc<super::SourceState>>,
+    }
+
+    #[glib::object_subclass]
+    impl ObjectSubclass for SourceBin {
+        const NAME: &'static str = "RsRelaySourceBin";
+        type Type = super::SourceBin;
+        type ParentType = gst::Bin;
+    }
+
+    impl ObjectImpl for SourceBin {}
+    impl GstObjectImpl for SourceBin {}
+    impl ElementImpl for SourceBin {}
+
+    impl BinImpl for SourceBin {
+        fn handle_message(&self, message: gst::Message) {
+            if let gst::MessageView::Error(err) = message.view() {
+                if let Some(source) = self.source.get() {
+                    source.record_error(&err.error().to_string());
+                }
+                return;
+            }
+            self.parent_handle_message(message)
+        }
+    }
+}
+
+glib::wrapper! {
+    pub struct SourceBin(ObjectSubclass<imp::SourceBin>) @extends gst::Bin, gst::Element, gst::Object;
+}
+
+impl SourceBin {
+    fn new(source: Arc<SourceState>) -> Self {
+        let bin: Self = glib::Object::new();
+        let _ = bin.imp().source.set(source);
+        bin
+    }
+}
+
+/// The encoder of a slate, only fed while the slate is shown or about to be,
+/// and counted by the governor meanwhile.
+struct SlateEncoder {
+    valve: glib::WeakRef<gst::Element>,
+    encoder: glib::WeakRef<gst::Element>,
+    governor: Arc<TranscodeGovernor>,
+    running: AtomicBool,
+}
+
+impl SlateEncoder {
+    fn set_running(&self, running: bool) {
+        if self.running.swap(running, Ordering::Relaxed) == running {
+            return;
+        }
+        if running {
+            self.governor.slate_started();
+        } else {
+            self.governor.slate_stopped();
+        }
+        if let (Some(valve), Some(encoder)) = (self.valve.upgrade(), self.encoder.upgrade()) {
+            set_slate_running(&valve, &encoder, running);
+        }
+    }
+}
+
+impl Drop for SlateEncoder {
+    fn drop(&mut self) {
+        if *self.running.get_mut() {
+            self.governor.slate_stopped();
+        }
+    }
+}
+
+struct SwitchState {
+    active: usize,
+    pending: Option<usize>,
+    connected: bool,
+}
+
+/// Picks which source the input selector lets through. Switches wait for a
+/// keyframe on the new source so viewers do not get a broken picture.
+struct FailoverSwitch {
+    sources: Vec<Arc<SourceState>>,
+    /// Index of the slate, always the last input, when the stream has one.
+    slate: Option<usize>,
+    slate_encoder: Option<SlateEncoder>,
+    bins: Vec<glib::WeakRef<SourceBin>>,
+    selector_pads: Vec<glib::WeakRef<gst::Pad>>,
+    selector: glib::WeakRef<gst::Element>,
+    stall_timeout: Duration,
+    runtime: Arc<StreamRuntime>,
+    state: Mutex<SwitchState>,
+}
+
+impl FailoverSwitch {
+    fn lock(&self) -> std::sync::MutexGuard<'_, SwitchState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Feeds the slate encoder while the slate is shown or switched to.
+    fn update_slate(&self, state: &SwitchState) {
+        let (Some(slate), Some(slate_encoder)) = (self.slate, &self.slate_encoder) else {
+            return;
+        };
+        slate_encoder.set_running(state.active == slate || state.pending == Some(slate));
+    }
+
+    fn keyframe_arrived(&self, index: usize) {
+        let mut state = self.lock();
+        if state.pending != Some(index) {
+            return;
+        }
+        let (Some(selector), Some(pad)) =
+            (self.selector.upgrade(), self.selector_pads[index].upgrade())
+        else {
+            return;
+        };
+        selector.set_property("active-pad", &pad);
+        let from = state.active;
+        state.active = index;
+        state.pending = None;
+        self.update_slate(&state);
+        drop(state);
+
+        self.runtime
+            .record_source_switch(from, index, self.slate == Some(index));
+    }
+
+    fn check(&self) -> glib::ControlFlow {
+        if self.selector.upgrade().is_none() {
+            if let Some(slate_encoder) = &self.slate_encoder {
+                slate_encoder.set_running(false);
+            }
+            return glib::ControlFlow::Break;
+        }
+
+        let now = Instant::now();
+        let mut healthy = Vec::with_capacity(self.sources.len());
+        let mut recovered = Vec::with_capacity(self.sources.len());
+        let mut restart = vec![];
+        for source in &self.sources {
+            let mut status = source.lock();
+            let is_slate = self.slate == Some(source.index);
+            // The slate is fed only while it is needed, it can always be
+            // switched to unless it failed.
+            let is_healthy = !status.failed
+                && (is_slate
+                    || status
+                        .last_buffer
+                        .is_some_and(|last_buffer| now - last_buffer < self.stall_timeout));
+            if !is_healthy {
+                status.healthy_since = None;
+            }
+            let idle_since = status.last_buffer.unwrap_or(status.started_at);
+            if !is_slate
+                && (status.failed
+                    || now - idle_since > self.stall_timeout.saturating_mul(RESTART_AFTER_STALLS))
+            {
+                restart.push(source.index);
+            }
+            healthy.push(is_healthy);
+            recovered.push(
+                status
+                    .healthy_since
+                    .is_some_and(|healthy_since| now - healthy_since >= self.stall_timeout),
+            );
+        }
+
+        let mut state = self.lock();
+        if state.pending.is_some_and(|pending| !healthy[pending]) {
+            state.pending = None;
+        }
+        let active_healthy = healthy[state.active];
+        let on_slate = self.slate == Some(state.active);
+        // Fall over to any healthy source, but only go back up the list once
+        // a source has been delivering for a whole stall timeout. Leaving the
+        // slate does not wait, anything beats it.
+        let preferred = (0..self.sources.len()).find(|&index| {
+            healthy[index]
+                && (!active_healthy || on_slate || (index < state.active && recovered[index]))
+        });
+        if let Some(preferred) = preferred {
+            if preferred != state.active && state.pending != Some(preferred) {
+                tracing::info!(
+                    "stream {} will switch from source {} to source {} on the next keyframe",
+                    self.runtime.stream_id,
+                    state.active,
+                    preferred
+                );
+                state.pending = Some(preferred);
+            }
+        }
+        self.update_slate(&state);
+
+        let any_healthy = healthy
+            .iter()
+            .enumerate()
+            .any(|(index, healthy)| *healthy && self.slate != Some(index));
+        let connected_changed = state.connected != any_healthy;
+        state.connected = any_healthy;
+        drop(state);
+        if connected_changed {
+            if any_healthy {
+                self.runtime.record_source_connected();
+            } else {
+                self.runtime.record_source_disconnected();
+            }
+        }
+
+        for index in restart {
+            self.restart(index);
+        }
+
+        glib::ControlFlow::Continue
+    }
+
+    fn restart(&self, index: usize) {
+        let Some(bin) = self.bins[index].upgrade() else {
+            return;
+        };
+        tracing::info!(
+            "restarting source {} of stream {}",
+            index,
+            self.runtime.stream_id
+        );
+        self.sources[index].reset();
+        // Stopping waits for the source to shut down, which must not hold up
+        // the main loop the monitor runs on.
+        bin.call_async(move |bin| {
+            if let Err(err) = bin.set_state(gst::State::Null) {
+                tracing::warn!("could not stop source {}: {:?}", index, err);
+                return;
+            }
+            if let Err(err) = bin.sync_state_with_parent() {
+                tracing::warn!("could not restart source {}: {:?}", index, err);
+            }
+        });
+    }
+}
+
+/// Adds `source_bin` to `bin` and links the parser inside it to a new input
+/// of `selector`.
+fn add_source_bin(
+    bin: &gst::Bin,
+    selector: &gst::Element,
+    source_bin: &SourceBin,
+    parse: &gst::Element,
+) -> Result<gst::Pad, PipelineBuildError> {
+    let parse_src = parse.static_pad("src").ok_or_else(|| PipelineBuildError {
+        reason: "h264parse has no src pad".to_string(),
+    })?;
+    let ghost_pad = gst::GhostPad::with_target(&parse_src)?;
+    source_bin.add_pad(&ghost_pad)?;
+    bin.add(source_bin)?;
+
+    let selector_pad =
+        selector
+            .request_pad_simple("sink_%u")
+            .ok_or_else(|| PipelineBuildError {
+                reason: "input-selector did not hand out a sink pad".to_string(),
+            })?;
+    ghost_pad
+        .link(&selector_pad)
+        .map_err(|err| PipelineBuildError {
+            reason: format!("could not link source bin: {:?}", err),
+        })?;
+
+    Ok(selector_pad)
+}
+
+/// Adds one source bin per source URL to `bin`, plus the slate when the
+/// stream has one, feeding an input selector, and returns the selector. A
+/// monitor on the main loop switches between the sources and restarts the
+/// ones that fail or stall.
+pub fn build_failover_source(
+    bin: &gst::Bin,
+    spec: &PipelineSpec,
+    runtime: &Arc<StreamRuntime>,
+) -> Result<gst::Element, PipelineBuildError> {
+    let selector = gst::ElementFactory::make("input-selector")
+        // Buffers of the inactive sources are dropped instead of waiting on
+        // the active one, so the monitor sees whether they are delivering.
+        .property("sync-streams", false)
+        .build()?;
+    bin.add(&selector)?;
+
+    let urls = std::iter::once(&spec.source_url).chain(&spec.backup_source_urls);
+    let mut sources = vec![];
+    let mut bins = vec![];
+    let mut selector_pads = vec![];
+    for (index, url) in urls.enumerate() {
+        let source = Arc::new(SourceState::new(index, runtime.clone()));
+        let source_bin = SourceBin::new(source.clone());
+        // Every IDR carries SPS and PPS so the stream can be switched to at
+        // any keyframe.
+        let parse = source_elements(
+            source_bin.upcast_ref(),
+            url,
+            spec.down_scale,
+            Some(-1),
+            || {},
+        )?;
+        let selector_pad = add_source_bin(bin, &selector, &source_bin, &parse)?;
+
+        sources.push(source);
+        bins.push(source_bin.downgrade());
+        selector_pads.push(selector_pad);
+    }
+
+    let mut slate_encoder = None;
+    let slate = match &spec.slate {
+        Some(slate_config) => {
+            let index = sources.len();
+            let (width, height) = if spec.down_scale {
+                // Encoded at the size the down scaling branch scales to.
+                down_scaled_size(&spec.transform)
+            } else {
+                let media_info = runtime.snapshot().media_info;
+                media_info
+                    .width
+                    .zip(media_info.height)
+                    .unwrap_or(DEFAULT_SLATE_SIZE)
+            };
+            let source = Arc::new(SourceState::new(index, runtime.clone()));
+            let source_bin = SourceBin::new(source.clone());
+            let elements = slate_elements(
+                source_bin.upcast_ref(),
+                slate_config,
+                width,
+                height,
+                &spec.transform,
+                &spec.codecs,
+            )?;
+            let selector_pad = add_source_bin(bin, &selector, &source_bin, &elements.parse)?;
+            slate_encoder = Some(SlateEncoder {
+                valve: elements.valve.downgrade(),
+                encoder: elements.encoder.downgrade(),
+                governor: spec.governor.clone(),
+                running: AtomicBool::new(false),
+            });
+
+            sources.push(source);
+            bins.push(source_bin.downgrade());
+            selector_pads.push(selector_pad);
+            Some(index)
+        }
+        None => None,
+    };
+
+    if let Some(primary) = selector_pads.first() {
+        selector.set_property("active-pad", primary);
+    }
+    let switch = Arc::new(FailoverSwitch {
+        sources,
+        slate,
+        slate_encoder,
+        bins,
+        selector_pads: selector_pads.iter().map(|pad| pad.downgrade()).collect(),
+        selector: selector.downgrade(),
+        stall_timeout: spec.stall_timeout,
+        runtime: runtime.clone(),
+        state: Mutex::new(SwitchState {
+            active: 0,
+            pending: None,
+            connected: false,
+        }),
+    });
+
+    for (index, selector_pad) in selector_pads.iter().enumerate() {
+        let switch = switch.clone();
+        selector_pad.add_probe(
+            gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM,
+            move |_, info| match info.data {
+                Some(gst::PadProbeData::Buffer(ref buffer)) => {
+                    switch.sources[index].record_buffer();
+                    if !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT) {
+                        switch.keyframe_arrived(index);
+                    }
+                    gst::PadProbeReturn::Ok
+                }
+                // One source ending must not end the stream, it is restarted.
+                Some(gst::PadProbeData::Event(ref event))
+                    if event.type_() == gst::EventType::Eos =>
+                {
+                    switch.sources[index].lock().failed = true;
+                    gst::PadProbeReturn::Drop
+                }
+                _ => gst::PadProbeReturn::Ok,
+            },
+        );
+    }
+
+    glib::timeout_add(MONITOR_INTERVAL, move || switch.check());
+
+    Ok(selector)
+}
@@ -0,0 +1,264 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// How often the CPU usage of the relay is sampled.
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Rate `/proc` counts CPU time in, `USER_HZ`. Assumed to be 100, its value
+/// on the common Linux architectures; `sysconf(_SC_CLK_TCK)` tells for sure.
+const CLOCK_TICKS_PER_SECOND: f64 = 100.0;
+
+/// Limits on transcoding, none meaning unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TranscodeLimits {
+    /// Most transcoded streams mounted at once, whether or not anyone is
+    /// watching them, plus the slates being encoded.
+    pub max_transcodes: Option<usize>,
+    /// CPU usage of the relay, in percent of all cores, from which no further
+    /// transcoded streams are added.
+    pub max_cpu_percent: Option<f64>,
+}
+
+/// Why a transcoded stream was not let in.
+#[derive(Debug)]
+pub enum TranscodeRejection {
+    TooManyTranscodes {
+        transcodes: usize,
+        max_transcodes: usize,
+    },
+    Overloaded {
+        cpu_percent: f64,
+        max_cpu_percent: f64,
+    },
+}
+
+#[derive(Debug, Default)]
+struct CpuUsage {
+    last_sample: Option<(Instant, f64)>,
+    percent: Option<f64>,
+}
+
+/// Keeps transcoding from taking the relay down: admits transcoded streams
+/// within the limits and counts the transcoding pipelines that run.
+#[derive(Debug, Default)]
+pub struct TranscodeGovernor {
+    limits: TranscodeLimits,
+    running_pipelines: AtomicUsize,
+    running_slates: AtomicUsize,
+    cpu: Mutex<CpuUsage>,
+}
+
+impl TranscodeGovernor {
+    pub fn new(limits: TranscodeLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Decides whether a stream may be transcoded next to the `transcodes`
+    /// transcoded streams already mounted and the slates being encoded.
+    ///
+    /// Mounted streams are counted rather than running pipelines: an
+    /// on-demand stream without viewers costs nothing now, but admitting it
+    /// promises to transcode it as soon as someone plays it.
+    pub fn admit(&self, transcodes: usize) -> Result<(), TranscodeRejection> {
+        let transcodes = transcodes + self.running_slates();
+        if let Some(max_transcodes) = self.limits.max_transcodes {
+            if transcodes >= max_transcodes {
+                return Err(TranscodeRejection::TooManyTranscodes {
+                    transcodes,
+                    max_transcodes,
+                });
+            }
+        }
+        if let (Some(max_cpu_percent), Some(cpu_percent)) =
+            (self.limits.max_cpu_percent, self.cpu_percent())
+        {
+            if cpu_percent >= max_cpu_percent {
+                return Err(TranscodeRejection::Overloaded {
+                    cpu_percent,
+                    max_cpu_percent,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Transcoding pipelines currently prepared.
+    pub fn running_pipelines(&self) -> usize {
+        self.running_pipelines.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn pipeline_started(&self) {
+        self.running_pipelines.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn pipeline_stopped(&self) {
+        let running = &self.running_pipelines;
+        let _ = running.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |running| {
+            running.checked_sub(1)
+        });
+    }
+
+    /// Slates currently encoded, each one an encoder like a transcode.
+    pub fn running_slates(&self) -> usize {
+        self.running_slates.load(Ordering::Relaxed)
+    }
+
+    pub(super) fn slate_started(&self) {
+        self.running_slates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn slate_stopped(&self) {
+        let running = &self.running_slates;
+        let _ = running.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |running| {
+            running.checked_sub(1)
+        });
+    }
+
+    /// CPU usage of the relay over the last sample interval, in percent of
+    /// all cores. `None` until two samples were taken or off Linux.
+    pub fn cpu_percent(&self) -> Option<f64> {
+        self.lock_cpu().percent
+    }
+
+    fn lock_cpu(&self) -> std::sync::MutexGuard<'_, CpuUsage> {
+        self.cpu
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn sample_cpu(&self) {
+        let Some(cpu_seconds) = process_cpu_seconds() else {
+            return;
+        };
+        let now = Instant::now();
+        let cores = std::thread::available_parallelism()
+            .map(|cores| cores.get())
+            .unwrap_or(1) as f64;
+
+        let mut cpu = self.lock_cpu();
+        if let Some((sampled_at, sampled_cpu_seconds)) = cpu.last_sample {
+            let elapsed = now.duration_since(sampled_at).as_secs_f64();
+            if elapsed > 0.0 {
+                cpu.percent = Some((cpu_seconds - sampled_cpu_seconds) / elapsed / cores * 100.0);
+            }
+        }
+        cpu.last_sample = Some((now, cpu_seconds));
+    }
+
+    /// Samples the CPU usage of the relay on the main loop from now on.
+    pub fn start_sampling(self: &Arc<Self>) {
+        let governor = self.clone();
+        governor.sample_cpu();
+        glib::timeout_add(CPU_SAMPLE_INTERVAL, move || {
+            governor.sample_cpu();
+            glib::ControlFlow::Continue
+        });
+    }
+}
+
+/// CPU time the relay used so far, all threads together.
+fn process_cpu_seconds() -> Option<f64> {
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    Some(parse_cpu_ticks(&stat)? / CLOCK_TICKS_PER_SECOND)
+}
+
+/// User and system CPU time in a `/proc/<pid>/stat` line, in clock ticks.
+fn parse_cpu_ticks(stat: &str) -> Option<f64> {
+    // The command name may hold spaces and parentheses, the fields after it
+    // do not. utime and stime are the 14th and 15th field, the 12th and 13th
+    // after the name.
+    let fields = stat
+        .rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let user_ticks = fields.get(11)?.parse::<f64>().ok()?;
+    let system_ticks = fields.get(12)?.parse::<f64>().ok()?;
+    Some(user_ticks + system_ticks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn governor(max_transcodes: Option<usize>, max_cpu_percent: Option<f64>) -> TranscodeGovernor {
+        TranscodeGovernor::new(TranscodeLimits {
+            max_transcodes,
+            max_cpu_percent,
+        })
+    }
+
+    #[test]
+    fn admits_below_the_transcode_limit() {
+        let governor = governor(Some(2), None);
+        assert!(governor.admit(1).is_ok());
+        assert!(matches!(
+            governor.admit(2),
+            Err(TranscodeRejection::TooManyTranscodes {
+                transcodes: 2,
+                max_transcodes: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn slates_count_as_transcodes() {
+        let governor = governor(Some(2), None);
+        governor.slate_started();
+        assert!(matches!(
+            governor.admit(1),
+            Err(TranscodeRejection::TooManyTranscodes { transcodes: 2, .. })
+        ));
+        governor.slate_stopped();
+        assert!(governor.admit(1).is_ok());
+        // Stopping more slates than started does not wrap around.
+        governor.slate_stopped();
+        assert_eq!(governor.running_slates(), 0);
+    }
+
+    #[test]
+    fn cpu_limit_applies_once_sampled() {
+        let governor = governor(None, Some(80.0));
+        assert!(governor.admit(100).is_ok());
+
+        governor.lock_cpu().percent = Some(79.9);
+        assert!(governor.admit(0).is_ok());
+        governor.lock_cpu().percent = Some(80.0);
+        assert!(matches!(
+            governor.admit(0),
+            Err(TranscodeRejection::Overloaded { .. })
+        ));
+    }
+
+    #[test]
+    fn admits_anything_without_limits() {
+        let governor = governor(None, None);
+        governor.lock_cpu().percent = Some(400.0);
+        assert!(governor.admit(usize::MAX / 2).is_ok());
+    }
+
+    #[test]
+    fn parses_cpu_ticks() {
+        let stat = "1234 (relay) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 9 0 42";
+        assert_eq!(parse_cpu_ticks(stat), Some(300.0));
+    }
+
+    #[test]
+    fn parses_cpu_ticks_after_odd_command_names() {
+        let stat = "1234 (my relay) (x) S 1 1234 1234 0 -1 4194560 100 0 0 0 250 50 0 0 20 0 9";
+        assert_eq!(parse_cpu_ticks(stat), Some(300.0));
+    }
+
+    #[test]
+    fn rejects_truncated_stat_lines() {
+        assert_eq!(parse_cpu_ticks("1234 (relay) S 1 1234"), None);
+        assert_eq!(parse_cpu_ticks("garbage"), None);
+    }
+}
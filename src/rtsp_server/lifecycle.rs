@@ -0,0 +1,282 @@
+use std::{
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use glib::{
+    object::{Cast, ObjectExt},
+    value::ToValue,
+};
+use gst_rtsp_server::{
+    prelude::{RTSPMediaExt, RTSPMediaFactoryExt},
+    RTSPMedia, RTSPMediaFactory,
+};
+use gstreamer as gst;
+use serde::{Deserialize, Serialize};
+
+use super::factory::RelayMediaFactory;
+
+const ALWAYS_ON_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// When a stream is connected to its upstream source.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMode {
+    /// The pipeline runs from the moment the stream is added, with or without
+    /// viewers.
+    AlwaysOn,
+    /// The source is pulled when the first viewer arrives and released once
+    /// the last one has been gone for the linger time.
+    #[default]
+    OnDemand,
+}
+
+#[derive(Default)]
+struct LifecycleState {
+    current: glib::WeakRef<RTSPMedia>,
+    held: Option<RTSPMedia>,
+    /// Bumped whenever a hold is taken, so release timers scheduled before it
+    /// know they are stale.
+    generation: u64,
+    stopped: bool,
+}
+
+/// Keeps the media of one stream prepared according to its `StreamMode`.
+///
+/// Medias are refcounted by the RTSP server: every session preparing one takes
+/// a reference and the pipeline is torn down when the last one is released.
+/// Holding an extra reference keeps the upstream connected without viewers.
+pub struct MediaLifecycle {
+    pub stream_id: String,
+    pub mode: StreamMode,
+    pub linger: Duration,
+    factory: glib::WeakRef<RTSPMediaFactory>,
+    state: Mutex<LifecycleState>,
+}
+
+impl MediaLifecycle {
+    pub fn new(
+        stream_id: &str,
+        mode: StreamMode,
+        linger: Duration,
+        factory: &RelayMediaFactory,
+    ) -> Self {
+        Self {
+            stream_id: stream_id.to_owned(),
+            mode,
+            linger,
+            factory: factory.upcast_ref::<RTSPMediaFactory>().downgrade(),
+            state: Mutex::new(LifecycleState::default()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, LifecycleState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Called from the factory whenever it creates a media for the stream.
+    pub fn media_configured(&self, media: &RTSPMedia) {
+        self.lock().current = media.downgrade();
+    }
+
+    /// Brings always-on streams up, on a thread of their own.
+    pub fn start(self: &Arc<Self>) {
+        if self.mode != StreamMode::AlwaysOn {
+            return;
+        }
+        spawn_bring_up(&self.stream_id, Arc::downgrade(self), None);
+    }
+
+    /// The held media of an always-on stream went down: release it and bring
+    /// the stream up again, unless it is being removed.
+    fn media_lost(self: &Arc<Self>, media: &RTSPMedia, reason: &str) {
+        let held = {
+            let mut state = self.lock();
+            if state.stopped || state.held.as_ref() != Some(media) {
+                return;
+            }
+            state.held.take()
+        };
+        tracing::warn!(
+            "stream {} {}, bringing it up again in {:?}",
+            self.stream_id,
+            reason,
+            ALWAYS_ON_RETRY_INTERVAL
+        );
+        spawn_bring_up(&self.stream_id, Arc::downgrade(self), held);
+    }
+
+    /// Releases the media for good, the stream is going away.
+    pub fn stop(&self) {
+        let held = {
+            let mut state = self.lock();
+            state.stopped = true;
+            state.held.take()
+        };
+        release(&self.stream_id, held);
+    }
+
+    /// The stream went from no viewers to one.
+    pub fn viewer_joined(&self) {
+        if self.mode == StreamMode::AlwaysOn || self.linger.is_zero() {
+            return;
+        }
+
+        let mut state = self.lock();
+        state.generation += 1;
+        if state.stopped || state.held.is_some() {
+            return;
+        }
+        let Some(media) = state.current.upgrade() else {
+            return;
+        };
+        match media.prepare(None) {
+            Ok(()) => state.held = Some(media),
+            Err(err) => tracing::warn!(
+                "could not hold the media of stream {}: {:?}",
+                self.stream_id,
+                err
+            ),
+        }
+    }
+
+    /// The last viewer of the stream left.
+    pub fn viewers_left(self: &Arc<Self>) {
+        if self.mode == StreamMode::AlwaysOn || self.linger.is_zero() {
+            return;
+        }
+
+        let generation = self.lock().generation;
+        let lifecycle = Arc::downgrade(self);
+        glib::timeout_add_once(self.linger, move || {
+            let Some(lifecycle) = lifecycle.upgrade() else {
+                return;
+            };
+            let held = {
+                let mut state = lifecycle.lock();
+                // A viewer came back while we were lingering.
+                if state.generation != generation {
+                    return;
+                }
+                state.held.take()
+            };
+            tracing::info!(
+                "no viewers on stream {} for {:?}, disconnecting the source",
+                lifecycle.stream_id,
+                lifecycle.linger
+            );
+            release(&lifecycle.stream_id, held);
+        });
+    }
+}
+
+/// Releases `lost`, the media that went down if any, and prepares a new one
+/// until it succeeds or the stream goes away. Preparing waits for the
+/// pipeline to preroll, which must not happen on the main loop: the media bus
+/// is watched there, and every other stream would freeze meanwhile.
+fn spawn_bring_up(stream_id: &str, lifecycle: Weak<MediaLifecycle>, lost: Option<RTSPMedia>) {
+    let stream_id = stream_id.to_owned();
+    let spawned = std::thread::Builder::new()
+        .name(format!("always-on-{}", stream_id))
+        .spawn(move || {
+            let mut delay = Duration::ZERO;
+            if lost.is_some() {
+                release(&stream_id, lost);
+                delay = ALWAYS_ON_RETRY_INTERVAL;
+            }
+            loop {
+                std::thread::sleep(delay);
+                let Some(lifecycle) = lifecycle.upgrade() else {
+                    return;
+                };
+                match bring_up(&lifecycle) {
+                    Ok(()) => return,
+                    Err(err) => tracing::warn!(
+                        "could not bring stream {} up, retrying in {:?}: {:?}",
+                        stream_id,
+                        ALWAYS_ON_RETRY_INTERVAL,
+                        err
+                    ),
+                }
+                delay = ALWAYS_ON_RETRY_INTERVAL;
+            }
+        });
+    if let Err(err) = spawned {
+        tracing::error!("could not bring stream {} up: {:?}", stream_id, err);
+    }
+}
+
+fn bring_up(lifecycle: &Arc<MediaLifecycle>) -> Result<(), glib::BoolError> {
+    {
+        let state = lifecycle.lock();
+        if state.stopped || state.held.is_some() {
+            return Ok(());
+        }
+    }
+    let Some(factory) = lifecycle.factory.upgrade() else {
+        return Ok(());
+    };
+
+    // The factory builds the pipeline on its own, the URL only has to parse.
+    let url = format!("rtsp://127.0.0.1/{}", lifecycle.stream_id);
+    let media = match gst_rtsp::RTSPUrl::parse(&url) {
+        (_, Some(url)) => factory
+            .construct(&url)
+            .and_then(|media| media.prepare(None).map(|_| media))?,
+        (result, None) => return Err(glib::bool_error!("invalid url {}: {:?}", url, result)),
+    };
+
+    let mut state = lifecycle.lock();
+    if state.stopped || state.held.is_some() {
+        drop(state);
+        release(&lifecycle.stream_id, Some(media));
+        return Ok(());
+    }
+    watch(lifecycle, &media);
+    state.held = Some(media);
+    tracing::info!("stream {} is up", lifecycle.stream_id);
+    Ok(())
+}
+
+/// Brings the stream up again when its held media is unprepared, fails or
+/// reaches the end of its source.
+fn watch(lifecycle: &Arc<MediaLifecycle>, media: &RTSPMedia) {
+    let unprepared = Arc::downgrade(lifecycle);
+    media.connect_unprepared(move |media| {
+        if let Some(lifecycle) = unprepared.upgrade() {
+            lifecycle.media_lost(media, "was unprepared");
+        }
+    });
+
+    let failed = Arc::downgrade(lifecycle);
+    media.connect("handle-message", false, move |args| {
+        let media = args.first().and_then(|value| value.get::<RTSPMedia>().ok());
+        let message = args
+            .get(1)
+            .and_then(|value| value.get::<gst::Message>().ok());
+        let reason = match message.as_ref().map(|message| message.view()) {
+            Some(gst::MessageView::Error(_)) => Some("failed"),
+            Some(gst::MessageView::Eos(_)) => Some("reached the end of its source"),
+            _ => None,
+        };
+        if let (Some(media), Some(reason), Some(lifecycle)) = (media, reason, failed.upgrade()) {
+            lifecycle.media_lost(&media, reason);
+        }
+        Some(true.to_value())
+    });
+}
+
+fn release(stream_id: &str, held: Option<RTSPMedia>) {
+    let Some(media) = held else {
+        return;
+    };
+    if let Err(err) = media.unprepare() {
+        tracing::warn!(
+            "could not release the media of stream {}: {:?}",
+            stream_id,
+            err
+        );
+    }
+}
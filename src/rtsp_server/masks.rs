@@ -0,0 +1,517 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex, RwLock,
+};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use gstreamer_video as gst_video;
+use serde::{Deserialize, Serialize};
+
+/// Size of the blocks blurred regions are pixelated into, in luma pixels.
+const BLUR_BLOCK_SIZE: usize = 16;
+const BLACK_LUMA: u8 = 16;
+const NEUTRAL_CHROMA: u8 = 128;
+
+/// How a masked region is hidden.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MaskStyle {
+    #[default]
+    Black,
+    /// Pixelated beyond recognition.
+    Blur,
+}
+
+/// A region of the picture hidden from viewers. Coordinates are fractions
+/// of the relayed picture, after cropping and turning, from the top left
+/// corner.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "shape", rename_all = "snake_case")]
+pub enum PrivacyMask {
+    Rectangle {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        #[serde(default)]
+        style: MaskStyle,
+    },
+    Polygon {
+        points: Vec<[f64; 2]>,
+        #[serde(default)]
+        style: MaskStyle,
+    },
+}
+
+#[derive(Debug)]
+pub struct MaskError {
+    pub reason: String,
+}
+
+fn is_normalized(value: f64) -> bool {
+    (0.0..=1.0).contains(&value)
+}
+
+impl PrivacyMask {
+    pub fn validate(&self) -> Result<(), MaskError> {
+        match self {
+            PrivacyMask::Rectangle {
+                x,
+                y,
+                width,
+                height,
+                ..
+            } => {
+                if ![x, y, width, height].into_iter().all(|v| is_normalized(*v)) {
+                    return Err(MaskError {
+                        reason: "coordinates must be between 0 and 1".to_string(),
+                    });
+                }
+                if *width == 0.0 || *height == 0.0 {
+                    return Err(MaskError {
+                        reason: "rectangle must have a width and a height".to_string(),
+                    });
+                }
+            }
+            PrivacyMask::Polygon { points, .. } => {
+                if points.len() < 3 {
+                    return Err(MaskError {
+                        reason: "polygon needs at least 3 points".to_string(),
+                    });
+                }
+                if !points.iter().flatten().all(|v| is_normalized(*v)) {
+                    return Err(MaskError {
+                        reason: "coordinates must be between 0 and 1".to_string(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn style(&self) -> MaskStyle {
+        match self {
+            PrivacyMask::Rectangle { style, .. } | PrivacyMask::Polygon { style, .. } => *style,
+        }
+    }
+
+    /// Pixels of a `width`x`height` plane covered by the mask, as row spans.
+    /// Pixels on the border count as covered.
+    fn spans(&self, width: usize, height: usize, spans: &mut Vec<Span>) {
+        let (w, h) = (width as f64, height as f64);
+        match self {
+            PrivacyMask::Rectangle {
+                x,
+                y,
+                width: mask_width,
+                height: mask_height,
+                ..
+            } => {
+                let start = (x * w).floor() as usize;
+                let end = (((x + mask_width) * w).ceil() as usize).min(width);
+                let first_row = (y * h).floor() as usize;
+                let last_row = (((y + mask_height) * h).ceil() as usize).min(height);
+                if start < end {
+                    spans.extend((first_row..last_row).map(|row| Span { row, start, end }));
+                }
+            }
+            PrivacyMask::Polygon { points, .. } => {
+                let mut crossings = vec![];
+                for row in 0..height {
+                    let center = (row as f64 + 0.5) / h;
+                    crossings.clear();
+                    for (index, [x1, y1]) in points.iter().enumerate() {
+                        let [x2, y2] = points[(index + 1) % points.len()];
+                        if (*y1 <= center) != (y2 <= center) {
+                            crossings.push(x1 + (center - y1) * (x2 - x1) / (y2 - y1));
+                        }
+                    }
+                    crossings.sort_by(f64::total_cmp);
+                    for pair in crossings.chunks_exact(2) {
+                        let start = (pair[0] * w).floor() as usize;
+                        let end = ((pair[1] * w).ceil() as usize).min(width);
+                        if start < end {
+                            spans.push(Span { row, start, end });
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    row: usize,
+    start: usize,
+    end: usize,
+}
+
+/// The masks of one plane of a frame.
+struct PlaneMask {
+    width: usize,
+    height: usize,
+    block_size: usize,
+    black: Vec<Span>,
+    blur: Vec<Span>,
+    /// Blocks the blurred spans fall in, as (column, row).
+    blur_blocks: Vec<(usize, usize)>,
+}
+
+impl PlaneMask {
+    fn new(masks: &[PrivacyMask], width: usize, height: usize, block_size: usize) -> Self {
+        let mut black = vec![];
+        let mut blur = vec![];
+        for mask in masks {
+            match mask.style() {
+                MaskStyle::Black => mask.spans(width, height, &mut black),
+                MaskStyle::Blur => mask.spans(width, height, &mut blur),
+            }
+        }
+        let mut blur_blocks: Vec<_> = blur
+            .iter()
+            .flat_map(|span| {
+                (span.start / block_size..=(span.end - 1) / block_size)
+                    .map(move |column| (column, span.row / block_size))
+            })
+            .collect();
+        blur_blocks.sort_unstable();
+        blur_blocks.dedup();
+
+        Self {
+            width,
+            height,
+            block_size,
+            black,
+            blur,
+            blur_blocks,
+        }
+    }
+
+    /// Masks a plane whose rows start `stride` bytes apart. Returns false
+    /// when `data` is too short to hold the plane.
+    fn apply(&self, data: &mut [u8], stride: usize, black_value: u8) -> bool {
+        if self.height > 0
+            && (stride < self.width || data.len() < stride * (self.height - 1) + self.width)
+        {
+            return false;
+        }
+        if !self.blur.is_empty() {
+            let columns = self.width.div_ceil(self.block_size);
+            let mut averages = vec![0u8; columns * self.height.div_ceil(self.block_size)];
+            for &(column, row) in &self.blur_blocks {
+                let x = column * self.block_size..((column + 1) * self.block_size).min(self.width);
+                let y = row * self.block_size..((row + 1) * self.block_size).min(self.height);
+                let count = (x.len() * y.len()) as u64;
+                let sum: u64 = y
+                    .map(|line| {
+                        data[line * stride + x.start..line * stride + x.end]
+                            .iter()
+                            .map(|value| *value as u64)
+                            .sum::<u64>()
+                    })
+                    .sum();
+                averages[row * columns + column] = (sum / count.max(1)) as u8;
+            }
+            for span in &self.blur {
+                let line = &mut data[span.row * stride..span.row * stride + self.width];
+                let block_row = span.row / self.block_size;
+                for (x, value) in line.iter_mut().enumerate().take(span.end).skip(span.start) {
+                    *value = averages[block_row * columns + x / self.block_size];
+                }
+            }
+        }
+        for span in &self.black {
+            data[span.row * stride + span.start..span.row * stride + span.end].fill(black_value);
+        }
+        true
+    }
+}
+
+/// Masks rasterized for one frame size.
+struct CompiledMasks {
+    width: usize,
+    height: usize,
+    luma: PlaneMask,
+    chroma: PlaneMask,
+}
+
+impl CompiledMasks {
+    fn new(masks: &[PrivacyMask], width: usize, height: usize) -> Self {
+        let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+        Self {
+            width,
+            height,
+            luma: PlaneMask::new(masks, width, height, BLUR_BLOCK_SIZE),
+            chroma: PlaneMask::new(masks, chroma_width, chroma_height, BLUR_BLOCK_SIZE / 2),
+        }
+    }
+
+    /// Masks an I420 frame, planes where its video meta or caps put them.
+    /// Returns false when the frame is not I420 of the compiled size.
+    fn apply(&self, frame: &mut gst_video::VideoFrameRef<&mut gst::BufferRef>) -> bool {
+        if frame.format() != gst_video::VideoFormat::I420
+            || frame.width() as usize != self.width
+            || frame.height() as usize != self.height
+        {
+            return false;
+        }
+
+        let planes = [
+            (&self.luma, BLACK_LUMA),
+            (&self.chroma, NEUTRAL_CHROMA),
+            (&self.chroma, NEUTRAL_CHROMA),
+        ];
+        for (plane, (mask, black_value)) in (0u32..).zip(planes) {
+            let Some(stride) = frame
+                .plane_stride()
+                .get(plane as usize)
+                .and_then(|stride| usize::try_from(*stride).ok())
+            else {
+                return false;
+            };
+            let Ok(data) = frame.plane_data_mut(plane) else {
+                return false;
+            };
+            if !mask.apply(data, stride, black_value) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Default)]
+struct MaskState {
+    masks: Vec<PrivacyMask>,
+    compiled: Option<Arc<CompiledMasks>>,
+}
+
+/// The privacy masks of a stream, shared with its running pipelines so that
+/// changes apply to the next frame.
+#[derive(Clone, Default)]
+pub struct PrivacyMasks {
+    state: Arc<RwLock<MaskState>>,
+}
+
+impl PrivacyMasks {
+    pub fn new(masks: Vec<PrivacyMask>) -> Self {
+        let privacy_masks = Self::default();
+        privacy_masks.set(masks);
+        privacy_masks
+    }
+
+    pub fn get(&self) -> Vec<PrivacyMask> {
+        self.state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .masks
+            .clone()
+    }
+
+    /// Replaces the masks. They have to be validated.
+    pub fn set(&self, masks: Vec<PrivacyMask>) {
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        state.masks = masks;
+        state.compiled = None;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.state
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .masks
+            .is_empty()
+    }
+
+    fn compiled(&self, width: usize, height: usize) -> Option<Arc<CompiledMasks>> {
+        {
+            let state = self
+                .state
+                .read()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if state.masks.is_empty() {
+                return None;
+            }
+            if let Some(compiled) = state
+                .compiled
+                .as_ref()
+                .filter(|compiled| compiled.width == width && compiled.height == height)
+            {
+                return Some(compiled.clone());
+            }
+        }
+
+        let mut state = self
+            .state
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let compiled = Arc::new(CompiledMasks::new(&state.masks, width, height));
+        state.compiled = Some(compiled.clone());
+        Some(compiled)
+    }
+}
+
+/// Masks the I420 frames leaving through `pad`. Frames that can not be
+/// masked are dropped rather than shown.
+pub(super) fn attach_masks(pad: &gst::Pad, masks: PrivacyMasks) {
+    let warned = AtomicBool::new(false);
+    let video_info = Mutex::new(None::<gst_video::VideoInfo>);
+    let probe_types = gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM;
+    pad.add_probe(probe_types, move |_, info| {
+        let buffer = match info.data {
+            Some(gst::PadProbeData::Event(ref event)) => {
+                if let gst::EventView::Caps(caps) = event.view() {
+                    *video_info
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+                        gst_video::VideoInfo::from_caps(caps.caps()).ok();
+                }
+                return gst::PadProbeReturn::Ok;
+            }
+            Some(gst::PadProbeData::Buffer(ref mut buffer)) => buffer,
+            _ => return gst::PadProbeReturn::Ok,
+        };
+        let Some(video_info) = video_info
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+        else {
+            // Without caps the planes can not be found.
+            return if masks.is_empty() {
+                gst::PadProbeReturn::Ok
+            } else {
+                gst::PadProbeReturn::Drop
+            };
+        };
+        let (width, height) = (video_info.width() as usize, video_info.height() as usize);
+        let Some(compiled) = masks.compiled(width, height) else {
+            return gst::PadProbeReturn::Ok;
+        };
+        let masked =
+            gst_video::VideoFrameRef::from_buffer_ref_writable(buffer.make_mut(), &video_info)
+                .is_ok_and(|mut frame| compiled.apply(&mut frame));
+        if masked {
+            return gst::PadProbeReturn::Ok;
+        }
+        if !warned.swap(true, Ordering::Relaxed) {
+            tracing::warn!("dropping frames that can not be masked");
+        }
+        gst::PadProbeReturn::Drop
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rectangle(x: f64, y: f64, width: f64, height: f64, style: MaskStyle) -> PrivacyMask {
+        PrivacyMask::Rectangle {
+            x,
+            y,
+            width,
+            height,
+            style,
+        }
+    }
+
+    fn spans(mask: &PrivacyMask, width: usize, height: usize) -> Vec<(usize, usize, usize)> {
+        let mut spans = vec![];
+        mask.spans(width, height, &mut spans);
+        spans
+            .into_iter()
+            .map(|span| (span.row, span.start, span.end))
+            .collect()
+    }
+
+    #[test]
+    fn rectangles_cover_their_border_pixels() {
+        let mask = rectangle(0.25, 0.5, 0.3, 0.25, MaskStyle::Black);
+        // 0.25 * 10 = 2.5 and 0.55 * 10 = 5.5, both partly covered.
+        assert_eq!(spans(&mask, 10, 8), vec![(4, 2, 6), (5, 2, 6)]);
+    }
+
+    #[test]
+    fn rectangles_are_clipped_to_the_plane() {
+        let mask = rectangle(0.5, 0.5, 0.5, 0.5, MaskStyle::Black);
+        assert_eq!(spans(&mask, 4, 4), vec![(2, 2, 4), (3, 2, 4)]);
+    }
+
+    #[test]
+    fn polygons_are_scan_filled() {
+        // A triangle with its tip at the top center.
+        let mask = PrivacyMask::Polygon {
+            points: vec![[0.5, 0.0], [1.0, 1.0], [0.0, 1.0]],
+            style: MaskStyle::Black,
+        };
+        assert_eq!(
+            spans(&mask, 8, 4),
+            vec![(0, 3, 5), (1, 2, 6), (2, 1, 7), (3, 0, 8)]
+        );
+    }
+
+    #[test]
+    fn polygons_with_holes_fill_between_crossings() {
+        // A U shape: the notch between x 0.25 and 0.75 is left open down to
+        // y 0.5.
+        let mask = PrivacyMask::Polygon {
+            points: vec![
+                [0.0, 0.0],
+                [0.25, 0.0],
+                [0.25, 0.5],
+                [0.75, 0.5],
+                [0.75, 0.0],
+                [1.0, 0.0],
+                [1.0, 1.0],
+                [0.0, 1.0],
+            ],
+            style: MaskStyle::Black,
+        };
+        assert_eq!(
+            spans(&mask, 4, 4),
+            vec![
+                (0, 0, 1),
+                (0, 3, 4),
+                (1, 0, 1),
+                (1, 3, 4),
+                (2, 0, 4),
+                (3, 0, 4)
+            ]
+        );
+    }
+
+    #[test]
+    fn planes_are_masked_within_their_stride() {
+        let masks = [rectangle(0.0, 0.0, 0.5, 0.5, MaskStyle::Black)];
+        let plane = PlaneMask::new(&masks, 4, 2, BLUR_BLOCK_SIZE);
+        // Rows of 4 pixels padded to 6 bytes.
+        let mut data = vec![200u8; 6 * 2];
+        assert!(plane.apply(&mut data, 6, BLACK_LUMA));
+        assert_eq!(
+            data,
+            vec![16, 16, 200, 200, 200, 200, 200, 200, 200, 200, 200, 200]
+        );
+    }
+
+    #[test]
+    fn blurred_blocks_take_their_average() {
+        let masks = [rectangle(0.0, 0.0, 1.0, 1.0, MaskStyle::Blur)];
+        let plane = PlaneMask::new(&masks, 4, 1, 2);
+        let mut data = vec![10, 20, 100, 200];
+        assert!(plane.apply(&mut data, 4, BLACK_LUMA));
+        assert_eq!(data, vec![15, 15, 150, 150]);
+    }
+
+    #[test]
+    fn short_planes_are_refused() {
+        let masks = [rectangle(0.0, 0.0, 1.0, 1.0, MaskStyle::Black)];
+        let plane = PlaneMask::new(&masks, 4, 2, BLUR_BLOCK_SIZE);
+        let mut data = vec![0u8; 7];
+        assert!(!plane.apply(&mut data, 4, BLACK_LUMA));
+        assert!(!plane.apply(&mut data, 3, BLACK_LUMA));
+    }
+}
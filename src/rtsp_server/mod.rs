@@ -1,6 +1,32 @@
+use std::{path::PathBuf, sync::Arc};
+
 use anyhow::Error;
 use derive_more::derive::{Display, Error};
-use gst_rtsp_server::{prelude::*, RTSPMedia, RTSPMountPoints};
+use gst_rtsp_server::{prelude::*, RTSPMedia, RTSPMountPoints, RTSPServer};
+
+use crate::{events::EventBus, net::cidr::parse_cidr_list};
+
+use self::{
+    access::{ClientAccessControl, ClientIpPolicy},
+    clients::ClientTracker,
+    codecs::{CodecConfig, Codecs},
+    governor::{TranscodeGovernor, TranscodeLimits},
+    slate::{SlateConfig, SLATE_PATTERNS},
+};
+
+pub mod access;
+pub mod clients;
+pub mod codecs;
+pub mod factory;
+pub mod failover;
+pub mod governor;
+pub mod lifecycle;
+pub mod masks;
+pub mod overlay;
+pub mod runtime;
+pub mod slate;
+pub mod transform;
+pub mod watchdog;
 
 #[derive(Debug, Display, Error)]
 #[display("Could not get mount points")]
@@ -8,9 +34,13 @@ struct NoMountPoints;
 mod auth {
 
     pub mod imp {
+        use std::sync::{Arc, OnceLock};
+
         use gst_rtsp::{RTSPHeaderField, RTSPStatusCode};
         use gst_rtsp_server::{prelude::*, subclass::prelude::*, RTSPContext};
 
+        use crate::rtsp_server::access::{client_ip, request_path, ClientAccessControl};
+
         impl Default for Auth {
             fn default() -> Self {
                 let user = std::env::var("RTSP_SERVER_USER").expect("SERVER_USER configuration missing");
@@ -20,12 +50,14 @@ mod auth {
                 Self {
                     user: user,
                     password: password,
+                    access_control: OnceLock::new(),
                 }
             }
         }
         pub struct Auth {
             pub user: String,
             pub password: String,
+            pub access_control: OnceLock<Arc<ClientAccessControl>>,
         }
 
         impl Auth {
@@ -45,6 +77,15 @@ mod auth {
             fn external_access_check(&self, user: &str) -> bool {
                 user == self.user
             }
+
+            fn client_address_check(&self, ctx: &RTSPContext) -> bool {
+                let Some(access_control) = self.access_control.get() else {
+                    return true;
+                };
+                let ip = ctx.client().and_then(|client| client_ip(&client));
+
+                access_control.permits(&request_path(ctx), ip)
+            }
         }
 
         #[glib::object_subclass]
@@ -83,6 +124,16 @@ mod auth {
                     return true;
                 }
 
+                if !self.client_address_check(ctx) {
+                    if let Some(resp) = ctx.response() {
+                        resp.init_response(RTSPStatusCode::Forbidden, ctx.request());
+                        if let Some(client) = ctx.client() {
+                            client.send_message(resp, ctx.session());
+                        }
+                    }
+                    return false;
+                }
+
                 if ctx.token().is_none() {
                     if !self.authenticate(ctx) {
                         if let Some(resp) = ctx.response() {
@@ -114,6 +165,12 @@ mod auth {
         }
     }
 
+    use std::sync::Arc;
+
+    use gst_rtsp_server::subclass::prelude::*;
+
+    use super::access::ClientAccessControl;
+
     glib::wrapper! {
         pub struct Auth(ObjectSubclass<imp::Auth>) @extends gst_rtsp_server::RTSPAuth;
     }
@@ -124,10 +181,27 @@ mod auth {
             glib::Object::new()
         }
     }
+
+    impl Auth {
+        pub fn new(access_control: Arc<ClientAccessControl>) -> Self {
+            let auth = Self::default();
+            let _ = auth.imp().access_control.set(access_control);
+            auth
+        }
+    }
 }
 pub struct MountServerResult {
     pub mount_points: RTSPMountPoints,
     pub root_url: String,
+    pub access_control: Arc<ClientAccessControl>,
+    pub clients: Arc<ClientTracker>,
+    pub slate: SlateConfig,
+    pub overlay_image_dir: Option<PathBuf>,
+    pub codecs: Codecs,
+    pub governor: Arc<TranscodeGovernor>,
+    pub server: RTSPServer,
+    pub probe_address: String,
+    pub source_id: glib::SourceId,
 }
 #[derive(Debug)]
 pub struct RTSPServerConfig {
@@ -136,6 +210,12 @@ pub struct RTSPServerConfig {
     pub port: String,
     pub user: String,
     pub password: String,
+    pub client_ip_policy: ClientIpPolicy,
+    pub max_clients: Option<usize>,
+    pub slate: SlateConfig,
+    pub overlay_image_dir: Option<PathBuf>,
+    pub codecs: CodecConfig,
+    pub transcode_limits: TranscodeLimits,
 }
 #[derive(Debug)]
 pub struct RTSPServerInitializationError {
@@ -164,23 +244,121 @@ pub fn load_rtsp_server_config() -> Result<RTSPServerConfig, RTSPServerReadConfi
         reason: format!("Failed to read RTSP_SERVER_PASSWORD from environment: {}", err),
     })?;
 
+    let allow = parse_cidr_list(&std::env::var("RTSP_CLIENT_ALLOWLIST").unwrap_or_default())
+        .map_err(|err| RTSPServerReadConfigError {
+            reason: format!("RTSP_CLIENT_ALLOWLIST is invalid: {}", err.reason),
+        })?;
+    let deny = parse_cidr_list(&std::env::var("RTSP_CLIENT_DENYLIST").unwrap_or_default())
+        .map_err(|err| RTSPServerReadConfigError {
+            reason: format!("RTSP_CLIENT_DENYLIST is invalid: {}", err.reason),
+        })?;
+
+    let max_clients = match std::env::var("RTSP_MAX_CLIENTS") {
+        Ok(value) if !value.is_empty() => Some(value.parse::<usize>().map_err(|err| RTSPServerReadConfigError {
+            reason: format!("RTSP_MAX_CLIENTS is invalid: {}", err),
+        })?),
+        _ => None,
+    };
+
+    let mut slate = SlateConfig::default();
+    if let Ok(image_path) = std::env::var("SLATE_IMAGE") {
+        if !image_path.is_empty() {
+            if !std::path::Path::new(&image_path).is_file() {
+                return Err(RTSPServerReadConfigError {
+                    reason: format!("SLATE_IMAGE {} is not a file", image_path),
+                });
+            }
+            slate.image_path = Some(image_path);
+        }
+    }
+    if let Ok(pattern) = std::env::var("SLATE_PATTERN") {
+        if !pattern.is_empty() {
+            if !SLATE_PATTERNS.contains(&pattern.as_str()) {
+                return Err(RTSPServerReadConfigError {
+                    reason: format!("SLATE_PATTERN {} is not a videotestsrc pattern", pattern),
+                });
+            }
+            slate.pattern = pattern;
+        }
+    }
+    if let Ok(text) = std::env::var("SLATE_TEXT") {
+        slate.text = text;
+    }
+
+    let overlay_image_dir = match std::env::var("OVERLAY_IMAGE_DIR") {
+        Ok(value) if !value.is_empty() => {
+            let overlay_image_dir = PathBuf::from(value);
+            if !overlay_image_dir.is_dir() {
+                return Err(RTSPServerReadConfigError {
+                    reason: format!("OVERLAY_IMAGE_DIR {} is not a directory", overlay_image_dir.display()),
+                });
+            }
+            Some(overlay_image_dir)
+        }
+        _ => None,
+    };
+
+    let codecs = CodecConfig::parse(
+        &std::env::var("H264_ENCODER").unwrap_or_default(),
+        &std::env::var("H264_DECODER").unwrap_or_default(),
+    )
+    .map_err(|err| RTSPServerReadConfigError {
+        reason: format!("H264_ENCODER or H264_DECODER is invalid: {}", err.reason),
+    })?;
+
+    let max_transcodes = match std::env::var("MAX_TRANSCODES") {
+        Ok(value) if !value.is_empty() => Some(value.parse::<usize>().map_err(|err| RTSPServerReadConfigError {
+            reason: format!("MAX_TRANSCODES is invalid: {}", err),
+        })?),
+        _ => None,
+    };
+    let max_cpu_percent = match std::env::var("MAX_TRANSCODE_CPU_PERCENT") {
+        Ok(value) if !value.is_empty() => {
+            let max_cpu_percent = value.parse::<f64>().map_err(|err| RTSPServerReadConfigError {
+                reason: format!("MAX_TRANSCODE_CPU_PERCENT is invalid: {}", err),
+            })?;
+            if !(max_cpu_percent > 0.0 && max_cpu_percent <= 100.0) {
+                return Err(RTSPServerReadConfigError {
+                    reason: "MAX_TRANSCODE_CPU_PERCENT must be between 0 and 100".to_string(),
+                });
+            }
+            Some(max_cpu_percent)
+        }
+        _ => None,
+    };
+
     Ok(RTSPServerConfig {
         host_address,
         host_name,
         port,
         user,
         password,
+        client_ip_policy: ClientIpPolicy { allow, deny },
+        max_clients,
+        slate,
+        overlay_image_dir,
+        codecs,
+        transcode_limits: TranscodeLimits {
+            max_transcodes,
+            max_cpu_percent,
+        },
     })
 }
 
-pub fn start_server(config: RTSPServerConfig) -> Result<MountServerResult, RTSPServerInitializationError> {
+pub fn start_server(config: RTSPServerConfig, events: EventBus) -> Result<MountServerResult, RTSPServerInitializationError> {
     gstreamer::init().map_err(|err| RTSPServerInitializationError {
         reason: format!("Failed to initialize GStreamer: {}", err),
     })?;
+    let codecs = Codecs::probe(config.codecs);
+    let governor = Arc::new(TranscodeGovernor::new(config.transcode_limits));
+    governor.start_sampling();
     let server = gst_rtsp_server::RTSPServer::new();
 
-    let auth = auth::Auth::default();
+    let access_control = Arc::new(ClientAccessControl::new(config.client_ip_policy));
+    let auth = auth::Auth::new(access_control.clone());
     server.set_auth(Some(&auth));
+    let clients = Arc::new(ClientTracker::new(config.max_clients, Some(config.user.clone())));
+    clients.attach(&server, events);
     tracing::info!("initializing rtsp server at: {}:{}", config.host_name, config.port);
     server.set_service(&config.port);
     server.set_address(&config.host_address);
@@ -191,13 +369,32 @@ pub fn start_server(config: RTSPServerConfig) -> Result<MountServerResult, RTSPS
         "rtsp://{}:{}@{}:{}/",
         config.user, config.password, config.host_name, config.port
     );
-    server.attach(None).map_err(|e| RTSPServerInitializationError {
+    let source_id = server.attach(None).map_err(|e| RTSPServerInitializationError {
         reason: format!("could not attach context due to error {:?}", e),
     })?;
+    let probe_host = match config.host_address.as_str() {
+        "0.0.0.0" => "127.0.0.1",
+        "::" => "::1",
+        host_address => host_address,
+    };
+    let probe_address = if probe_host.contains(':') {
+        format!("[{}]:{}", probe_host, config.port)
+    } else {
+        format!("{}:{}", probe_host, config.port)
+    };
 
     let res = MountServerResult {
         mount_points: mounts,
         root_url,
+        access_control,
+        clients,
+        slate: config.slate,
+        overlay_image_dir: config.overlay_image_dir,
+        codecs,
+        governor,
+        server,
+        probe_address,
+        source_id,
     };
     Ok(res)
 }
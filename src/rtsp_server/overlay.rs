@@ -0,0 +1,302 @@
+use std::path::{Path, PathBuf};
+
+use gstreamer as gst;
+use serde::{Deserialize, Serialize};
+
+use super::factory::PipelineBuildError;
+
+/// Distance in pixels between overlays and the edges of the picture.
+const OVERLAY_MARGIN: i32 = 16;
+const OVERLAY_FONT: &str = "Sans 14";
+const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S %Z";
+
+/// Where an overlay is drawn on the picture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlayPosition {
+    #[default]
+    TopLeft,
+    TopCenter,
+    TopRight,
+    BottomLeft,
+    BottomCenter,
+    BottomRight,
+}
+
+impl OverlayPosition {
+    fn alignment(self) -> (&'static str, &'static str) {
+        match self {
+            OverlayPosition::TopLeft => ("left", "top"),
+            OverlayPosition::TopCenter => ("center", "top"),
+            OverlayPosition::TopRight => ("right", "top"),
+            OverlayPosition::BottomLeft => ("left", "bottom"),
+            OverlayPosition::BottomCenter => ("center", "bottom"),
+            OverlayPosition::BottomRight => ("right", "bottom"),
+        }
+    }
+}
+
+/// An overlay as configured on a stream.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Overlay {
+    /// The name of the stream.
+    CameraName {
+        #[serde(default)]
+        position: OverlayPosition,
+    },
+    /// The wall-clock time of the relay, `format` being a strftime format.
+    Timestamp {
+        #[serde(default)]
+        position: OverlayPosition,
+        #[serde(default)]
+        format: Option<String>,
+    },
+    Text {
+        text: String,
+        #[serde(default)]
+        position: OverlayPosition,
+    },
+    /// An image from the overlay image directory, by file name.
+    Logo {
+        image: String,
+        #[serde(default)]
+        position: OverlayPosition,
+    },
+}
+
+#[derive(Debug)]
+pub struct OverlayError {
+    pub reason: String,
+}
+
+/// An overlay with everything it refers to looked up, ready to be put in the
+/// pipeline.
+#[derive(Debug, Clone)]
+pub enum OverlaySpec {
+    Text {
+        text: String,
+        position: OverlayPosition,
+    },
+    Clock {
+        format: String,
+        position: OverlayPosition,
+    },
+    Image {
+        path: PathBuf,
+        position: OverlayPosition,
+    },
+}
+
+impl Overlay {
+    pub fn resolve(
+        &self,
+        stream_name: &str,
+        image_dir: Option<&Path>,
+    ) -> Result<OverlaySpec, OverlayError> {
+        match self {
+            Overlay::CameraName { position } => Ok(OverlaySpec::Text {
+                text: stream_name.to_owned(),
+                position: *position,
+            }),
+            Overlay::Timestamp { position, format } => Ok(OverlaySpec::Clock {
+                format: format
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_TIMESTAMP_FORMAT.to_owned()),
+                position: *position,
+            }),
+            Overlay::Text { text, position } => {
+                if text.is_empty() {
+                    return Err(OverlayError {
+                        reason: "text overlays need a text".to_string(),
+                    });
+                }
+                Ok(OverlaySpec::Text {
+                    text: text.clone(),
+                    position: *position,
+                })
+            }
+            Overlay::Logo { image, position } => {
+                let Some(image_dir) = image_dir else {
+                    return Err(OverlayError {
+                        reason: "logo overlays are disabled, OVERLAY_IMAGE_DIR is not set"
+                            .to_string(),
+                    });
+                };
+                if matches!(
+                    position,
+                    OverlayPosition::TopCenter | OverlayPosition::BottomCenter
+                ) {
+                    return Err(OverlayError {
+                        reason: "logo overlays can only be placed in a corner".to_string(),
+                    });
+                }
+                // Only plain file names, nothing outside the directory.
+                if image.is_empty() || image.starts_with('.') || image.contains(['/', '\\']) {
+                    return Err(OverlayError {
+                        reason: format!("{} is not a valid image name", image),
+                    });
+                }
+                let path = image_dir.join(image);
+                if !path.is_file() {
+                    return Err(OverlayError {
+                        reason: format!("image {} does not exist", image),
+                    });
+                }
+                Ok(OverlaySpec::Image {
+                    path,
+                    position: *position,
+                })
+            }
+        }
+    }
+}
+
+/// Builds one element per overlay, to be chained on raw video.
+pub(super) fn overlay_elements(
+    overlays: &[OverlaySpec],
+) -> Result<Vec<gst::Element>, PipelineBuildError> {
+    overlays
+        .iter()
+        .map(|overlay| {
+            let element = match overlay {
+                OverlaySpec::Text { text, position } => {
+                    let (halignment, valignment) = position.alignment();
+                    gst::ElementFactory::make("textoverlay")
+                        .property("text", text)
+                        .property_from_str("halignment", halignment)
+                        .property_from_str("valignment", valignment)
+                        .property("xpad", OVERLAY_MARGIN)
+                        .property("ypad", OVERLAY_MARGIN)
+                        .property("font-desc", OVERLAY_FONT)
+                        .property("shaded-background", true)
+                        .build()?
+                }
+                OverlaySpec::Clock { format, position } => {
+                    let (halignment, valignment) = position.alignment();
+                    gst::ElementFactory::make("clockoverlay")
+                        .property("time-format", format)
+                        .property_from_str("halignment", halignment)
+                        .property_from_str("valignment", valignment)
+                        .property("xpad", OVERLAY_MARGIN)
+                        .property("ypad", OVERLAY_MARGIN)
+                        .property("font-desc", OVERLAY_FONT)
+                        .property("shaded-background", true)
+                        .build()?
+                }
+                OverlaySpec::Image { path, position } => {
+                    // Negative offsets are taken from the right and bottom
+                    // edges.
+                    let (offset_x, offset_y) = match position {
+                        OverlayPosition::TopLeft => (OVERLAY_MARGIN, OVERLAY_MARGIN),
+                        OverlayPosition::TopRight => (-OVERLAY_MARGIN, OVERLAY_MARGIN),
+                        OverlayPosition::BottomLeft => (OVERLAY_MARGIN, -OVERLAY_MARGIN),
+                        _ => (-OVERLAY_MARGIN, -OVERLAY_MARGIN),
+                    };
+                    gst::ElementFactory::make("gdkpixbufoverlay")
+                        .property("location", path.to_string_lossy().as_ref())
+                        .property("offset-x", offset_x)
+                        .property("offset-y", offset_y)
+                        .build()?
+                }
+            };
+            Ok(element)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An image directory holding `logo.png` and `.hidden`, with `secret.png`
+    /// next to it.
+    fn image_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("overlay-{}-{}", std::process::id(), name));
+        let dir = root.join("images");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("logo.png"), b"").unwrap();
+        std::fs::write(dir.join(".hidden"), b"").unwrap();
+        std::fs::write(root.join("secret.png"), b"").unwrap();
+        dir
+    }
+
+    fn logo(image: &str, position: OverlayPosition) -> Overlay {
+        Overlay::Logo {
+            image: image.to_string(),
+            position,
+        }
+    }
+
+    #[test]
+    fn logos_resolve_to_files_in_the_image_dir() {
+        let dir = image_dir("resolve");
+        let spec = logo("logo.png", OverlayPosition::BottomRight)
+            .resolve("cam1", Some(&dir))
+            .unwrap();
+        assert!(matches!(
+            spec,
+            OverlaySpec::Image { path, position: OverlayPosition::BottomRight }
+                if path == dir.join("logo.png")
+        ));
+    }
+
+    #[test]
+    fn logo_names_can_not_leave_the_image_dir() {
+        let dir = image_dir("names");
+        for image in ["../secret.png", "a/b", "images\\logo.png", ".hidden", ""] {
+            let result = logo(image, OverlayPosition::TopLeft).resolve("cam1", Some(&dir));
+            assert!(result.is_err(), "{:?} was accepted", image);
+        }
+    }
+
+    #[test]
+    fn logos_need_an_existing_file() {
+        let dir = image_dir("missing");
+        assert!(logo("missing.png", OverlayPosition::TopLeft)
+            .resolve("cam1", Some(&dir))
+            .is_err());
+    }
+
+    #[test]
+    fn logos_need_an_image_dir() {
+        assert!(logo("logo.png", OverlayPosition::TopLeft)
+            .resolve("cam1", None)
+            .is_err());
+    }
+
+    #[test]
+    fn logos_can_not_be_centered() {
+        let dir = image_dir("centered");
+        for position in [OverlayPosition::TopCenter, OverlayPosition::BottomCenter] {
+            assert!(logo("logo.png", position)
+                .resolve("cam1", Some(&dir))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn text_overlays_default_to_the_top_left() {
+        let camera_name: Overlay = serde_json::from_str(r#"{"kind":"camera_name"}"#).unwrap();
+        assert!(matches!(
+            camera_name.resolve("Lobby", None).unwrap(),
+            OverlaySpec::Text { text, position: OverlayPosition::TopLeft } if text == "Lobby"
+        ));
+
+        let timestamp: Overlay = serde_json::from_str(r#"{"kind":"timestamp"}"#).unwrap();
+        assert!(matches!(
+            timestamp.resolve("Lobby", None).unwrap(),
+            OverlaySpec::Clock { format, position: OverlayPosition::TopLeft }
+                if format == DEFAULT_TIMESTAMP_FORMAT
+        ));
+    }
+
+    #[test]
+    fn text_overlays_need_a_text() {
+        let text = Overlay::Text {
+            text: String::new(),
+            position: OverlayPosition::TopLeft,
+        };
+        assert!(text.resolve("cam1", None).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use prometheus::{IntCounter, IntGauge};
+
+use crate::{
+    events::{EventBus, StreamEventType},
+    metrics::RelayMetrics,
+};
+
+const BITRATE_WINDOW: Duration = Duration::from_secs(2);
+/// Timestamp discontinuities of a stream are logged at most this often, a
+/// broken source can produce one with every frame.
+const DISCONTINUITY_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Format of the video coming from the upstream source, as negotiated on the
+/// source side of the pipeline.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMediaInfo {
+    pub codec: Option<String>,
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub framerate: Option<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RuntimeSnapshot {
+    pub upstream_connected: bool,
+    /// Index of the source being relayed, 0 being the primary.
+    pub active_source: usize,
+    /// Whether viewers are shown the slate because no source delivers.
+    pub slate_active: bool,
+    /// The pipeline is playing but no frames arrive.
+    pub stalled: bool,
+    /// Frames arrive but the picture does not change.
+    pub frozen: bool,
+    pub timestamp_discontinuities: u64,
+    pub last_discontinuity_at: Option<chrono::DateTime<Utc>>,
+    pub media_info: SourceMediaInfo,
+    pub bitrate_bps: u64,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<chrono::DateTime<Utc>>,
+}
+
+struct BitrateWindow {
+    started_at: Instant,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct DiscontinuityWarnings {
+    warned_at: Option<Instant>,
+    /// Discontinuities not logged since the last warning.
+    suppressed: u64,
+}
+
+/// Per stream hooks the relay pipeline reports into. One instance lives as
+/// long as the stream is mounted and is shared by every media the factory
+/// creates for it.
+pub struct StreamRuntime {
+    pub stream_id: String,
+    source_connections: AtomicU64,
+    relayed_bytes: IntCounter,
+    relayed_frames: IntCounter,
+    source_reconnects: IntCounter,
+    pipeline_errors: IntCounter,
+    source_switches: IntCounter,
+    stalled: IntGauge,
+    frozen: IntGauge,
+    stalls: IntCounter,
+    freezes: IntCounter,
+    timestamp_discontinuities: IntCounter,
+    status: Mutex<RuntimeSnapshot>,
+    bitrate_window: Mutex<BitrateWindow>,
+    discontinuity_warnings: Mutex<DiscontinuityWarnings>,
+    events: EventBus,
+}
+
+impl StreamRuntime {
+    pub fn new(stream_id: &str, metrics: &Arc<RelayMetrics>, events: EventBus) -> Self {
+        Self {
+            stream_id: stream_id.to_owned(),
+            source_connections: AtomicU64::new(0),
+            relayed_bytes: metrics.relayed_bytes.with_label_values(&[stream_id]),
+            relayed_frames: metrics.relayed_frames.with_label_values(&[stream_id]),
+            source_reconnects: metrics.source_reconnects.with_label_values(&[stream_id]),
+            pipeline_errors: metrics.pipeline_errors.with_label_values(&[stream_id]),
+            source_switches: metrics.source_switches.with_label_values(&[stream_id]),
+            stalled: metrics.stream_stalled.with_label_values(&[stream_id]),
+            frozen: metrics.stream_frozen.with_label_values(&[stream_id]),
+            stalls: metrics.stream_stalls.with_label_values(&[stream_id]),
+            freezes: metrics.stream_freezes.with_label_values(&[stream_id]),
+            timestamp_discontinuities: metrics
+                .timestamp_discontinuities
+                .with_label_values(&[stream_id]),
+            status: Mutex::new(RuntimeSnapshot::default()),
+            bitrate_window: Mutex::new(BitrateWindow {
+                started_at: Instant::now(),
+                bytes: 0,
+            }),
+            discontinuity_warnings: Mutex::new(DiscontinuityWarnings::default()),
+            events,
+        }
+    }
+
+    fn update_status(&self, update: impl FnOnce(&mut RuntimeSnapshot)) {
+        let mut status = self
+            .status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let was_connected = status.upstream_connected;
+        update(&mut status);
+        let is_connected = status.upstream_connected;
+        let last_error = status.last_error.clone();
+        drop(status);
+
+        if was_connected != is_connected {
+            let (event_type, details) = if is_connected {
+                (StreamEventType::SourceConnected, None)
+            } else {
+                (
+                    StreamEventType::SourceDisconnected,
+                    last_error.map(|last_error| serde_json::json!({ "last_error": last_error })),
+                )
+            };
+            self.events.publish(event_type, &self.stream_id, details);
+        }
+    }
+
+    pub fn snapshot(&self) -> RuntimeSnapshot {
+        let mut snapshot = self
+            .status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let window = self
+            .bitrate_window
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Nothing flowed for a whole window, the last computed rate is stale.
+        if window.started_at.elapsed() > BITRATE_WINDOW * 2 {
+            snapshot.bitrate_bps = 0;
+        }
+        snapshot
+    }
+
+    pub fn record_frame(&self, size: usize) {
+        self.relayed_bytes.inc_by(size as u64);
+        self.relayed_frames.inc();
+
+        let mut window = self
+            .bitrate_window
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        window.bytes += size as u64;
+        let elapsed = window.started_at.elapsed();
+        if elapsed >= BITRATE_WINDOW {
+            let bitrate_bps = (window.bytes as f64 * 8.0 / elapsed.as_secs_f64()) as u64;
+            window.started_at = Instant::now();
+            window.bytes = 0;
+            drop(window);
+            self.update_status(|status| status.bitrate_bps = bitrate_bps);
+        }
+    }
+
+    pub fn record_source_connected(&self) {
+        if self.source_connections.fetch_add(1, Ordering::Relaxed) > 0 {
+            self.source_reconnects.inc();
+        }
+        self.update_status(|status| status.upstream_connected = true);
+    }
+
+    pub fn record_source_disconnected(&self) {
+        self.update_status(|status| {
+            status.upstream_connected = false;
+            status.bitrate_bps = 0;
+        });
+    }
+
+    pub fn record_media_info(&self, media_info: SourceMediaInfo) {
+        self.update_status(|status| status.media_info = media_info);
+    }
+
+    pub fn record_pipeline_error(&self, message: &str) {
+        tracing::error!("pipeline error on stream {}: {}", self.stream_id, message);
+        self.pipeline_errors.inc();
+        self.update_status(|status| {
+            status.upstream_connected = false;
+            status.last_error = Some(message.to_owned());
+            status.last_error_at = Some(Utc::now());
+        });
+    }
+
+    /// An error on one of several sources. The stream keeps going on the
+    /// others, so only the last error is recorded.
+    pub fn record_source_error(&self, source: usize, message: &str) {
+        tracing::error!(
+            "error on source {} of stream {}: {}",
+            source,
+            self.stream_id,
+            message
+        );
+        self.pipeline_errors.inc();
+        let mut status = self
+            .status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.last_error = Some(format!("source {}: {}", source, message));
+        status.last_error_at = Some(Utc::now());
+    }
+
+    pub fn record_source_switch(&self, from: usize, to: usize, slate: bool) {
+        if slate {
+            tracing::warn!(
+                "stream {} has no working source, showing the slate",
+                self.stream_id
+            );
+        } else {
+            tracing::info!(
+                "stream {} switched from source {} to source {}",
+                self.stream_id,
+                from,
+                to
+            );
+        }
+        self.source_switches.inc();
+        {
+            let mut status = self
+                .status
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            status.active_source = to;
+            status.slate_active = slate;
+        }
+        self.events.publish(
+            StreamEventType::SourceSwitched,
+            &self.stream_id,
+            Some(serde_json::json!({ "from": from, "to": to, "slate": slate })),
+        );
+    }
+
+    pub fn record_stalled(&self, stalled: bool) {
+        if stalled {
+            tracing::warn!("stream {} stalled, no frames are arriving", self.stream_id);
+            self.stalls.inc();
+        } else {
+            tracing::info!("stream {} is receiving frames again", self.stream_id);
+        }
+        self.stalled.set(stalled as i64);
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .stalled = stalled;
+    }
+
+    pub fn record_frozen(&self, frozen: bool) {
+        if frozen {
+            tracing::warn!(
+                "stream {} froze, the picture is not changing",
+                self.stream_id
+            );
+            self.freezes.inc();
+        } else {
+            tracing::info!("stream {} is not frozen anymore", self.stream_id);
+        }
+        self.frozen.set(frozen as i64);
+        self.status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .frozen = frozen;
+    }
+
+    pub fn record_timestamp_discontinuity(
+        &self,
+        previous: gstreamer::ClockTime,
+        current: gstreamer::ClockTime,
+    ) {
+        let mut warnings = self
+            .discontinuity_warnings
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if warnings
+            .warned_at
+            .is_some_and(|warned_at| warned_at.elapsed() < DISCONTINUITY_WARNING_INTERVAL)
+        {
+            warnings.suppressed += 1;
+        } else {
+            tracing::warn!(
+                "timestamp discontinuity on stream {}: {} followed by {} ({} more since the last \
+                 warning)",
+                self.stream_id,
+                previous,
+                current,
+                warnings.suppressed
+            );
+            warnings.warned_at = Some(Instant::now());
+            warnings.suppressed = 0;
+        }
+        drop(warnings);
+        self.timestamp_discontinuities.inc();
+        let mut status = self
+            .status
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        status.timestamp_discontinuities += 1;
+        status.last_discontinuity_at = Some(Utc::now());
+    }
+}
@@ -0,0 +1,219 @@
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+use super::{
+    codecs::Codecs,
+    factory::PipelineBuildError,
+    transform::{counter_transform_element, VideoTransform},
+};
+
+const SLATE_FRAMERATE: i32 = 25;
+
+/// Nicks of the `videotestsrc` patterns, checked when the configuration is
+/// read because GStreamer is not initialized yet at that point.
+pub const SLATE_PATTERNS: &[&str] = &[
+    "smpte",
+    "snow",
+    "black",
+    "white",
+    "red",
+    "green",
+    "blue",
+    "checkers-1",
+    "checkers-2",
+    "checkers-4",
+    "checkers-8",
+    "circular",
+    "blink",
+    "smpte75",
+    "zone-plate",
+    "gamut",
+    "chroma-zone-plate",
+    "solid-color",
+    "ball",
+    "smpte100",
+    "bar",
+    "pinwheel",
+    "spokes",
+    "gradient",
+    "colors",
+    "smpte-rp-219",
+];
+
+/// What viewers of a stream are shown while none of its sources deliver.
+#[derive(Debug, Clone)]
+pub struct SlateConfig {
+    /// Still image shown instead of the test pattern.
+    pub image_path: Option<String>,
+    pub pattern: String,
+    /// Text drawn over the slate, nothing when empty.
+    pub text: String,
+}
+
+impl Default for SlateConfig {
+    fn default() -> Self {
+        Self {
+            image_path: None,
+            pattern: "black".to_owned(),
+            text: "Camera offline".to_owned(),
+        }
+    }
+}
+
+/// The elements of a slate the failover switch drives.
+pub(super) struct SlateElements {
+    /// Last element of the slate, like `source_elements` returns for a camera.
+    pub parse: gst::Element,
+    /// Drops the pictures before they are drawn on and encoded, closed while
+    /// the slate is not shown so its encoder idles.
+    pub valve: gst::Element,
+    pub encoder: gst::Element,
+}
+
+/// Adds the elements rendering and encoding the slate to `bin`. Players see
+/// a `width`x`height` picture, the format of the source. The slate goes
+/// through `transform` like the sources do, so it is turned to come out
+/// upright and drawn into the part of the picture the crop keeps. The valve
+/// starts closed.
+pub(super) fn slate_elements(
+    bin: &gst::Bin,
+    config: &SlateConfig,
+    width: i32,
+    height: i32,
+    transform: &VideoTransform,
+    codecs: &Codecs,
+) -> Result<SlateElements, PipelineBuildError> {
+    // The slate is encoded the way round the sources deliver.
+    let (encoded_width, encoded_height) = if transform.transposes() {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let margins = transform
+        .crop
+        .map(|crop| crop.margins(encoded_width, encoded_height));
+    let (drawn_width, drawn_height) = match margins {
+        Some(margins) => {
+            let kept_width = encoded_width - margins.left - margins.right;
+            let kept_height = encoded_height - margins.top - margins.bottom;
+            if transform.transposes() {
+                (kept_height, kept_width)
+            } else {
+                (kept_width, kept_height)
+            }
+        }
+        None => (width, height),
+    };
+    let raw_caps = gst::Caps::builder("video/x-raw")
+        .field("width", drawn_width)
+        .field("height", drawn_height)
+        .field("framerate", gst::Fraction::new(SLATE_FRAMERATE, 1))
+        .build();
+
+    let mut chain = vec![];
+    match &config.image_path {
+        Some(image_path) => {
+            let file = gst::ElementFactory::make("filesrc")
+                .property("location", image_path)
+                .build()?;
+            let decode = gst::ElementFactory::make("decodebin").build()?;
+            let convert = gst::ElementFactory::make("videoconvert").build()?;
+            bin.add_many([&file, &decode])?;
+            file.link(&decode)?;
+
+            let convert_weak = convert.downgrade();
+            decode.connect_pad_added(move |_, src_pad| {
+                let Some(sink_pad) = convert_weak
+                    .upgrade()
+                    .and_then(|convert| convert.static_pad("sink"))
+                else {
+                    return;
+                };
+                if sink_pad.is_linked() {
+                    return;
+                }
+                if let Err(err) = src_pad.link(&sink_pad) {
+                    tracing::warn!("could not link slate image: {:?}", err);
+                }
+            });
+
+            chain.push(convert);
+            chain.push(
+                gst::ElementFactory::make("imagefreeze")
+                    .property("is-live", true)
+                    .build()?,
+            );
+            chain.push(gst::ElementFactory::make("videoscale").build()?);
+            chain.push(gst::ElementFactory::make("videoconvert").build()?);
+        }
+        None => {
+            chain.push(
+                gst::ElementFactory::make("videotestsrc")
+                    .property("is-live", true)
+                    .property_from_str("pattern", &config.pattern)
+                    .build()?,
+            );
+        }
+    }
+    chain.push(
+        gst::ElementFactory::make("capsfilter")
+            .property("caps", &raw_caps)
+            .build()?,
+    );
+    let valve = gst::ElementFactory::make("valve")
+        .property("drop", true)
+        .build()?;
+    chain.push(valve.clone());
+    if !config.text.is_empty() {
+        chain.push(
+            gst::ElementFactory::make("textoverlay")
+                .property("text", &config.text)
+                .property_from_str("valignment", "center")
+                .property_from_str("halignment", "center")
+                .property("font-desc", "Sans Bold 32")
+                .build()?,
+        );
+    }
+    chain.extend(counter_transform_element(transform)?);
+    // Negative videobox borders add the margins the crop takes away again.
+    if let Some(margins) = margins {
+        chain.push(
+            gst::ElementFactory::make("videobox")
+                .property("left", -margins.left)
+                .property("top", -margins.top)
+                .property("right", -margins.right)
+                .property("bottom", -margins.bottom)
+                .build()?,
+        );
+    }
+    chain.push(gst::ElementFactory::make("videoconvert").build()?);
+    let encoder = codecs.encoder(200, SLATE_FRAMERATE as u32)?;
+    chain.push(encoder.clone());
+    let parse = gst::ElementFactory::make("h264parse")
+        .property("config-interval", -1i32)
+        .build()?;
+    chain.push(parse.clone());
+
+    bin.add_many(&chain)?;
+    gst::Element::link_many(&chain)?;
+
+    Ok(SlateElements {
+        parse,
+        valve,
+        encoder,
+    })
+}
+
+/// Opens or closes the valve of a slate. The encoder is asked for a keyframe
+/// when it is opened, the failover switch waits for one before showing it.
+pub(super) fn set_slate_running(valve: &gst::Element, encoder: &gst::Element, running: bool) {
+    valve.set_property("drop", !running);
+    if running {
+        let force_key_unit = gst::Structure::builder("GstForceKeyUnit")
+            .field("all-headers", true)
+            .build();
+        if !encoder.send_event(gst::event::CustomUpstream::new(force_key_unit)) {
+            tracing::debug!("slate encoder did not take the keyframe request");
+        }
+    }
+}
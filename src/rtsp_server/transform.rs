@@ -0,0 +1,320 @@
+use gstreamer as gst;
+use gstreamer::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::factory::PipelineBuildError;
+
+/// Clockwise rotation of the picture.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "u16", into = "u16")]
+pub enum Rotation {
+    #[default]
+    None,
+    Clockwise90,
+    Clockwise180,
+    Clockwise270,
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = String;
+
+    fn try_from(degrees: u16) -> Result<Self, Self::Error> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Clockwise90),
+            180 => Ok(Rotation::Clockwise180),
+            270 => Ok(Rotation::Clockwise270),
+            other => Err(format!("rotation must be 0, 90, 180 or 270, not {}", other)),
+        }
+    }
+}
+
+impl From<Rotation> for u16 {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::None => 0,
+            Rotation::Clockwise90 => 90,
+            Rotation::Clockwise180 => 180,
+            Rotation::Clockwise270 => 270,
+        }
+    }
+}
+
+impl Rotation {
+    fn half_turn(self) -> Self {
+        match self {
+            Rotation::None => Rotation::Clockwise180,
+            Rotation::Clockwise90 => Rotation::Clockwise270,
+            Rotation::Clockwise180 => Rotation::None,
+            Rotation::Clockwise270 => Rotation::Clockwise90,
+        }
+    }
+}
+
+/// Part of the picture kept, in fractions of the source picture size.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Crop {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Pixels `Crop` cuts off each side of a picture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct CropMargins {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32,
+}
+
+impl Crop {
+    pub(super) fn margins(&self, width: i32, height: i32) -> CropMargins {
+        let (width, height) = (width as f64, height as f64);
+        CropMargins {
+            left: (self.x * width).round() as i32,
+            top: (self.y * height).round() as i32,
+            right: (width - ((self.x + self.width) * width).round()).max(0.0) as i32,
+            bottom: (height - ((self.y + self.height) * height).round()).max(0.0) as i32,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TransformError {
+    pub reason: String,
+}
+
+/// Geometry fixes applied to transcoded streams: the picture is cropped,
+/// then flipped, then rotated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VideoTransform {
+    #[serde(default)]
+    pub crop: Option<Crop>,
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub flip_horizontal: bool,
+    #[serde(default)]
+    pub flip_vertical: bool,
+}
+
+impl VideoTransform {
+    pub fn is_identity(&self) -> bool {
+        *self == VideoTransform::default()
+    }
+
+    pub fn validate(&self) -> Result<(), TransformError> {
+        let Some(crop) = self.crop else {
+            return Ok(());
+        };
+        if ![crop.x, crop.y, crop.width, crop.height]
+            .iter()
+            .all(|value| (0.0..=1.0).contains(value))
+        {
+            return Err(TransformError {
+                reason: "crop coordinates must be between 0 and 1".to_string(),
+            });
+        }
+        if crop.width == 0.0 || crop.height == 0.0 {
+            return Err(TransformError {
+                reason: "crop must have a width and a height".to_string(),
+            });
+        }
+        // Some slack for sums like 0.7 + 0.3.
+        if crop.x + crop.width > 1.0 + 1e-9 || crop.y + crop.height > 1.0 + 1e-9 {
+            return Err(TransformError {
+                reason: "crop must lie within the picture".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether width and height trade places.
+    pub fn transposes(&self) -> bool {
+        matches!(
+            self.rotation,
+            Rotation::Clockwise90 | Rotation::Clockwise270
+        )
+    }
+
+    /// The flips and the rotation as a single `videoflip` direction.
+    fn direction(&self) -> &'static str {
+        // Flipping both ways is turning by half, and flipping vertically is
+        // flipping horizontally and turning by half.
+        let (rotation, flip) = match (self.flip_horizontal, self.flip_vertical) {
+            (false, false) => (self.rotation, false),
+            (true, true) => (self.rotation.half_turn(), false),
+            (true, false) => (self.rotation, true),
+            (false, true) => (self.rotation.half_turn(), true),
+        };
+        match (rotation, flip) {
+            (Rotation::None, false) => "identity",
+            (Rotation::Clockwise90, false) => "90r",
+            (Rotation::Clockwise180, false) => "180",
+            (Rotation::Clockwise270, false) => "90l",
+            (Rotation::None, true) => "horiz",
+            (Rotation::Clockwise90, true) => "ur-ll",
+            (Rotation::Clockwise180, true) => "vert",
+            (Rotation::Clockwise270, true) => "ul-lr",
+        }
+    }
+
+    /// The direction undoing `direction`.
+    fn inverse_direction(&self) -> &'static str {
+        match self.direction() {
+            "90r" => "90l",
+            "90l" => "90r",
+            direction => direction,
+        }
+    }
+}
+
+/// Builds the elements cropping, flipping and rotating raw video.
+pub(super) fn transform_elements(
+    transform: &VideoTransform,
+) -> Result<Vec<gst::Element>, PipelineBuildError> {
+    let mut elements = vec![];
+    if let Some(crop) = transform.crop {
+        let videocrop = gst::ElementFactory::make("videocrop").build()?;
+        // videocrop counts pixels, which are only known once the caps are.
+        if let Some(sink) = videocrop.static_pad("sink") {
+            let videocrop_weak = videocrop.downgrade();
+            sink.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+                let Some(gst::PadProbeData::Event(ref event)) = info.data else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let gst::EventView::Caps(caps) = event.view() else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let Some(structure) = caps.caps().structure(0) else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let (Ok(width), Ok(height), Some(videocrop)) = (
+                    structure.get::<i32>("width"),
+                    structure.get::<i32>("height"),
+                    videocrop_weak.upgrade(),
+                ) else {
+                    return gst::PadProbeReturn::Ok;
+                };
+                let margins = crop.margins(width, height);
+                videocrop.set_property("left", margins.left);
+                videocrop.set_property("top", margins.top);
+                videocrop.set_property("right", margins.right);
+                videocrop.set_property("bottom", margins.bottom);
+                gst::PadProbeReturn::Ok
+            });
+        }
+        elements.push(videocrop);
+    }
+    if transform.direction() != "identity" {
+        elements.push(
+            gst::ElementFactory::make("videoflip")
+                .property_from_str("video-direction", transform.direction())
+                .build()?,
+        );
+    }
+    Ok(elements)
+}
+
+/// Builds a `videoflip` turning a picture so that it comes out upright
+/// after `transform`, for pictures the relay draws itself.
+pub(super) fn counter_transform_element(
+    transform: &VideoTransform,
+) -> Result<Option<gst::Element>, PipelineBuildError> {
+    if transform.direction() == "identity" {
+        return Ok(None);
+    }
+    Ok(Some(
+        gst::ElementFactory::make("videoflip")
+            .property_from_str("video-direction", transform.inverse_direction())
+            .build()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transform(rotation: u16, flip_horizontal: bool, flip_vertical: bool) -> VideoTransform {
+        VideoTransform {
+            crop: None,
+            rotation: Rotation::try_from(rotation).unwrap(),
+            flip_horizontal,
+            flip_vertical,
+        }
+    }
+
+    #[test]
+    fn rotations_map_to_videoflip_directions() {
+        assert_eq!(transform(0, false, false).direction(), "identity");
+        assert_eq!(transform(90, false, false).direction(), "90r");
+        assert_eq!(transform(180, false, false).direction(), "180");
+        assert_eq!(transform(270, false, false).direction(), "90l");
+    }
+
+    #[test]
+    fn flips_combine_with_rotations() {
+        let table = [
+            (0, true, false, "horiz"),
+            (0, false, true, "vert"),
+            (0, true, true, "180"),
+            (90, true, false, "ur-ll"),
+            (90, false, true, "ul-lr"),
+            (90, true, true, "90l"),
+            (180, true, false, "vert"),
+            (180, false, true, "horiz"),
+            (180, true, true, "identity"),
+            (270, true, false, "ul-lr"),
+            (270, false, true, "ur-ll"),
+            (270, true, true, "90r"),
+        ];
+        for (rotation, flip_horizontal, flip_vertical, direction) in table {
+            assert_eq!(
+                transform(rotation, flip_horizontal, flip_vertical).direction(),
+                direction,
+                "rotation {} flip_horizontal {} flip_vertical {}",
+                rotation,
+                flip_horizontal,
+                flip_vertical
+            );
+        }
+    }
+
+    #[test]
+    fn inverse_directions_undo_the_turn() {
+        assert_eq!(transform(90, false, false).inverse_direction(), "90l");
+        assert_eq!(transform(270, false, false).inverse_direction(), "90r");
+        // Flips and half turns are their own inverse.
+        assert_eq!(transform(180, false, false).inverse_direction(), "180");
+        assert_eq!(transform(90, true, false).inverse_direction(), "ur-ll");
+        assert_eq!(transform(0, false, true).inverse_direction(), "vert");
+    }
+
+    #[test]
+    fn only_quarter_turns_transpose() {
+        assert!(transform(90, false, false).transposes());
+        assert!(transform(270, true, false).transposes());
+        assert!(!transform(180, false, true).transposes());
+    }
+
+    #[test]
+    fn crop_margins_add_up_to_the_picture() {
+        let crop = Crop {
+            x: 0.25,
+            y: 0.1,
+            width: 0.5,
+            height: 0.7,
+        };
+        assert_eq!(
+            crop.margins(640, 320),
+            CropMargins {
+                left: 160,
+                top: 32,
+                right: 160,
+                bottom: 64,
+            }
+        );
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use gstreamer as gst;
+use gstreamer::prelude::*;
+
+use super::runtime::StreamRuntime;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the picture has to stay unchanged before the stream counts as
+/// frozen.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Delta frames up to this size carry no picture change, an encoder fed the
+/// same image over and over produces nothing but these. Meant for pictures
+/// up to `STATIC_FRAME_REFERENCE_PIXELS`, larger ones are split into more
+/// slices and get a proportionally larger threshold.
+const MAX_STATIC_DELTA_FRAME_SIZE: usize = 128;
+/// Keyframes of an unchanged picture only differ in their slice headers.
+const MAX_STATIC_KEYFRAME_SIZE_DIFFERENCE: usize = 16;
+/// Picture size the static frame thresholds are meant for, 1280x720.
+const STATIC_FRAME_REFERENCE_PIXELS: u64 = 1280 * 720;
+/// Largest jump between the timestamps of consecutive frames that is not
+/// reported as a discontinuity.
+const MAX_TIMESTAMP_GAP: gst::ClockTime = gst::ClockTime::from_seconds(2);
+/// Keyframe intervals remembered on keyframes-only streams. The longest of
+/// them sets how long such a stream may go without a frame.
+const KEYFRAME_INTERVAL_SAMPLES: usize = 8;
+/// Keyframes that may be missed in a row before a keyframes-only stream
+/// counts as stalled.
+const MISSED_KEYFRAMES_BEFORE_STALL: u32 = 2;
+
+/// Frame sizes up to which the picture counts as unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct StaticFrameThresholds {
+    max_delta_frame_size: usize,
+    max_keyframe_size_difference: usize,
+}
+
+impl StaticFrameThresholds {
+    fn for_picture(width: u64, height: u64) -> Self {
+        let scale = (width * height)
+            .div_ceil(STATIC_FRAME_REFERENCE_PIXELS)
+            .max(1) as usize;
+        Self {
+            max_delta_frame_size: MAX_STATIC_DELTA_FRAME_SIZE * scale,
+            max_keyframe_size_difference: MAX_STATIC_KEYFRAME_SIZE_DIFFERENCE * scale,
+        }
+    }
+}
+
+impl Default for StaticFrameThresholds {
+    fn default() -> Self {
+        Self {
+            max_delta_frame_size: MAX_STATIC_DELTA_FRAME_SIZE,
+            max_keyframe_size_difference: MAX_STATIC_KEYFRAME_SIZE_DIFFERENCE,
+        }
+    }
+}
+
+/// Size of the pictures `pay` is fed, once its caps are negotiated.
+fn picture_size(pay: &gst::Element) -> Option<(u64, u64)> {
+    let caps = pay.static_pad("sink")?.current_caps()?;
+    let structure = caps.structure(0)?;
+    let width = structure.get::<i32>("width").ok()?;
+    let height = structure.get::<i32>("height").ok()?;
+    Some((u64::try_from(width).ok()?, u64::try_from(height).ok()?))
+}
+
+#[derive(Default)]
+struct WatchdogState {
+    playing_since: Option<Instant>,
+    last_buffer: Option<Instant>,
+    /// Decode timestamp of the last frame, its presentation timestamp when it
+    /// had none. Presentation timestamps go back and forth with B-frames.
+    last_timestamp: Option<gst::ClockTime>,
+    static_thresholds: StaticFrameThresholds,
+    /// Time between the last keyframes, on keyframes-only streams.
+    keyframe_intervals: VecDeque<Duration>,
+    last_keyframe_size: Option<usize>,
+    unchanged_since: Option<Instant>,
+    stalled: bool,
+    frozen: bool,
+}
+
+/// Watches the frames a media hands to its payloader and reports streams
+/// that stall, freeze or jump in time to the stream runtime.
+///
+/// Frames are not decoded, a frozen picture is recognized by the encoder
+/// output it leads to: empty delta frames and keyframes of the same size.
+pub struct StreamWatchdog {
+    runtime: Arc<StreamRuntime>,
+    stall_timeout: Duration,
+    /// Only keyframes are relayed, a whole GOP apart, so only timestamps
+    /// going backwards are discontinuities and the stall timeout stretches
+    /// to the keyframe interval.
+    keyframes_only: bool,
+    pay: glib::WeakRef<gst::Element>,
+    state: Mutex<WatchdogState>,
+}
+
+impl StreamWatchdog {
+    /// Starts watching the frames reaching `pay`. The checks run on the main
+    /// loop until the element is gone.
+    pub fn start(
+        pay: &gst::Element,
+        stall_timeout: Duration,
+        keyframes_only: bool,
+        runtime: Arc<StreamRuntime>,
+    ) -> Arc<Self> {
+        let watchdog = Arc::new(Self {
+            runtime,
+            stall_timeout,
+            keyframes_only,
+            pay: pay.downgrade(),
+            state: Mutex::new(WatchdogState::default()),
+        });
+        let monitor = watchdog.clone();
+        glib::timeout_add(CHECK_INTERVAL, move || monitor.check());
+        watchdog
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WatchdogState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Called from the streaming thread for every frame.
+    pub fn frame(&self, buffer: &gst::BufferRef) {
+        let now = Instant::now();
+        let keyframe = !buffer.flags().contains(gst::BufferFlags::DELTA_UNIT);
+        let size = buffer.size();
+
+        let mut state = self.lock();
+        let previous_buffer = state.last_buffer.replace(now);
+        // A gap that was reported as a stall is not the keyframe interval.
+        if let Some(previous_buffer) =
+            previous_buffer.filter(|_| self.keyframes_only && !state.stalled)
+        {
+            if state.keyframe_intervals.len() == KEYFRAME_INTERVAL_SAMPLES {
+                state.keyframe_intervals.pop_front();
+            }
+            state.keyframe_intervals.push_back(now - previous_buffer);
+        }
+
+        let thresholds = state.static_thresholds;
+        let unchanged = if keyframe {
+            let unchanged = state.last_keyframe_size.is_some_and(|last_size| {
+                last_size.abs_diff(size) <= thresholds.max_keyframe_size_difference
+            });
+            state.last_keyframe_size = Some(size);
+            unchanged
+        } else {
+            size <= thresholds.max_delta_frame_size
+        };
+        if unchanged {
+            state.unchanged_since.get_or_insert(now);
+        } else {
+            state.unchanged_since = None;
+        }
+
+        let Some(timestamp) = buffer.dts().or(buffer.pts()) else {
+            return;
+        };
+        let previous = state.last_timestamp.replace(timestamp);
+        drop(state);
+        if let Some(previous) = previous {
+            if timestamp < previous
+                || (!self.keyframes_only && timestamp - previous > MAX_TIMESTAMP_GAP)
+            {
+                self.runtime
+                    .record_timestamp_discontinuity(previous, timestamp);
+            }
+        }
+    }
+
+    fn check(&self) -> glib::ControlFlow {
+        let Some(pay) = self.pay.upgrade() else {
+            let state = self.lock();
+            let (stalled, frozen) = (state.stalled, state.frozen);
+            drop(state);
+            if stalled {
+                self.runtime.record_stalled(false);
+            }
+            if frozen {
+                self.runtime.record_frozen(false);
+            }
+            return glib::ControlFlow::Break;
+        };
+        let playing = pay.current_state() == gst::State::Playing;
+        let static_thresholds = picture_size(&pay)
+            .map(|(width, height)| StaticFrameThresholds::for_picture(width, height));
+        // The slate never changes, that is not the camera freezing.
+        let on_slate = self.runtime.snapshot().slate_active;
+
+        let now = Instant::now();
+        let mut state = self.lock();
+        if let Some(static_thresholds) = static_thresholds {
+            state.static_thresholds = static_thresholds;
+        }
+        if !playing {
+            state.playing_since = None;
+        } else if state.playing_since.is_none() {
+            state.playing_since = Some(now);
+        }
+        // Keyframes-only streams deliver a frame per GOP, which may well be
+        // longer than the stall timeout.
+        let longest_keyframe_interval = state
+            .keyframe_intervals
+            .iter()
+            .max()
+            .copied()
+            .unwrap_or_default();
+        let stall_timeout = self
+            .stall_timeout
+            .max(longest_keyframe_interval.saturating_mul(MISSED_KEYFRAMES_BEFORE_STALL));
+        let stalled = state.playing_since.is_some_and(|playing_since| {
+            let idle_since = state
+                .last_buffer
+                .map_or(playing_since, |last_buffer| last_buffer.max(playing_since));
+            now - idle_since > stall_timeout
+        });
+        let frozen = playing
+            && !stalled
+            && !on_slate
+            && state
+                .unchanged_since
+                .is_some_and(|unchanged_since| now - unchanged_since >= FREEZE_TIMEOUT);
+        let stalled_changed = state.stalled != stalled;
+        let frozen_changed = state.frozen != frozen;
+        state.stalled = stalled;
+        state.frozen = frozen;
+        drop(state);
+
+        if stalled_changed {
+            self.runtime.record_stalled(stalled);
+        }
+        if frozen_changed {
+            self.runtime.record_frozen(frozen);
+        }
+
+        glib::ControlFlow::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_frame_thresholds_grow_with_the_picture() {
+        let small = StaticFrameThresholds::for_picture(640, 360);
+        assert_eq!(small, StaticFrameThresholds::default());
+        assert_eq!(StaticFrameThresholds::for_picture(1280, 720), small);
+
+        let uhd = StaticFrameThresholds::for_picture(3840, 2160);
+        assert_eq!(uhd.max_delta_frame_size, MAX_STATIC_DELTA_FRAME_SIZE * 9);
+        assert_eq!(
+            uhd.max_keyframe_size_difference,
+            MAX_STATIC_KEYFRAME_SIZE_DIFFERENCE * 9
+        );
+    }
+}
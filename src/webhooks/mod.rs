@@ -0,0 +1,266 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use ulid::Ulid;
+
+use crate::events::{EventBus, StreamEvent, StreamEventType};
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_LOG_SIZE: usize = 500;
+
+/// Events webhooks receive when they do not pick their own.
+pub const DEFAULT_WEBHOOK_EVENTS: [StreamEventType; 4] = [
+    StreamEventType::StreamAdded,
+    StreamEventType::StreamRemoved,
+    StreamEventType::StreamExpired,
+    StreamEventType::SourceDisconnected,
+];
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<StreamEventType>,
+    pub created_at: String,
+    #[serde(skip)]
+    secret: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeliveryRecord {
+    pub delivery_id: String,
+    pub webhook_id: String,
+    pub url: String,
+    pub event_type: StreamEventType,
+    pub stream_id: String,
+    pub attempt: u32,
+    pub attempted_at: String,
+    pub status_code: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookSignError {
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookClientError {
+    pub reason: String,
+}
+
+/// Registered webhooks and the log of recent delivery attempts.
+pub struct WebhookRegistry {
+    webhooks: RwLock<Vec<Webhook>>,
+    deliveries: Mutex<VecDeque<DeliveryRecord>>,
+    default_secret: Option<String>,
+    client: reqwest::Client,
+}
+
+impl WebhookRegistry {
+    pub fn new(default_secret: Option<String>) -> Result<Self, WebhookClientError> {
+        // Redirects could lead deliveries to hosts the allowlist rejects.
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .map_err(|err| WebhookClientError {
+                reason: format!("could not build the webhook client: {:?}", err),
+            })?;
+        Ok(Self {
+            webhooks: RwLock::new(vec![]),
+            deliveries: Mutex::new(VecDeque::with_capacity(DELIVERY_LOG_SIZE)),
+            default_secret,
+            client,
+        })
+    }
+
+    /// Registers a webhook. Falls back to the configured secret when none is
+    /// given and returns `None` when there is no secret to sign with.
+    pub fn register(
+        &self,
+        url: String,
+        events: Vec<StreamEventType>,
+        secret: Option<String>,
+    ) -> Option<Webhook> {
+        let secret = secret.or_else(|| self.default_secret.clone())?;
+        let webhook = Webhook {
+            id: Ulid::new().to_string(),
+            url,
+            events: if events.is_empty() {
+                DEFAULT_WEBHOOK_EVENTS.to_vec()
+            } else {
+                events
+            },
+            created_at: Utc::now().to_rfc3339(),
+            secret,
+        };
+        self.webhooks
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(webhook.clone());
+
+        Some(webhook)
+    }
+
+    pub fn remove(&self, id: &str) -> bool {
+        let mut webhooks = self
+            .webhooks
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let webhooks_count = webhooks.len();
+        webhooks.retain(|webhook| webhook.id != id);
+        webhooks.len() < webhooks_count
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.webhooks
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn deliveries(&self) -> Vec<DeliveryRecord> {
+        self.deliveries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    fn record_delivery(&self, record: DeliveryRecord) {
+        let mut deliveries = self
+            .deliveries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if deliveries.len() == DELIVERY_LOG_SIZE {
+            deliveries.pop_front();
+        }
+        deliveries.push_back(record);
+    }
+
+    fn subscribers(&self, event_type: StreamEventType) -> Vec<Webhook> {
+        self.webhooks
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .filter(|webhook| webhook.events.contains(&event_type))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Signs `"{timestamp}.{body}"` so receivers can reject replayed payloads.
+fn sign(secret: &str, timestamp: &str, body: &str) -> Result<String, WebhookSignError> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).map_err(|err| WebhookSignError {
+            reason: err.to_string(),
+        })?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    Ok(hex::encode(mac.finalize().into_bytes()))
+}
+
+async fn deliver(registry: Arc<WebhookRegistry>, webhook: Webhook, event: StreamEvent) {
+    let body = match serde_json::to_string(&event) {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::error!("could not serialize webhook payload: {:?}", err);
+            return;
+        }
+    };
+    let delivery_id = Ulid::new().to_string();
+
+    for attempt in 1..=MAX_DELIVERY_ATTEMPTS {
+        let timestamp = Utc::now().timestamp().to_string();
+        let signature = match sign(&webhook.secret, &timestamp, &body) {
+            Ok(signature) => signature,
+            Err(err) => {
+                tracing::error!("could not sign webhook payload: {}", err.reason);
+                return;
+            }
+        };
+        let result = registry
+            .client
+            .post(&webhook.url)
+            .timeout(DELIVERY_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Relay-Delivery", &delivery_id)
+            .header("X-Relay-Event", event.event_type.as_str())
+            .header("X-Relay-Timestamp", &timestamp)
+            .header("X-Relay-Signature", format!("sha256={}", signature))
+            .body(body.clone())
+            .send()
+            .await;
+
+        let (status_code, error) = match result {
+            Ok(response) if response.status().is_success() => (Some(response.status()), None),
+            Ok(response) => (
+                Some(response.status()),
+                Some(format!("receiver responded with {}", response.status())),
+            ),
+            Err(err) => (err.status(), Some(err.to_string())),
+        };
+        let delivered = error.is_none();
+        registry.record_delivery(DeliveryRecord {
+            delivery_id: delivery_id.clone(),
+            webhook_id: webhook.id.clone(),
+            url: webhook.url.clone(),
+            event_type: event.event_type,
+            stream_id: event.stream_id.clone(),
+            attempt,
+            attempted_at: Utc::now().to_rfc3339(),
+            status_code: status_code.map(|status_code| status_code.as_u16()),
+            error: error.clone(),
+            delivered,
+        });
+
+        if delivered {
+            return;
+        }
+        tracing::warn!(
+            "webhook delivery {} to {} failed on attempt {}: {:?}",
+            delivery_id,
+            webhook.url,
+            attempt,
+            error
+        );
+        if attempt < MAX_DELIVERY_ATTEMPTS {
+            tokio::time::sleep(Duration::from_secs(1 << attempt)).await;
+        }
+    }
+}
+
+/// Forwards events from the bus to every subscribed webhook. Each delivery
+/// retries on its own task so a slow receiver does not hold up the others.
+pub async fn run_dispatcher(registry: Arc<WebhookRegistry>, events: EventBus) {
+    let mut receiver = events.subscribe();
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::warn!(
+                    "webhook dispatcher lagged behind, {} events skipped",
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        for webhook in registry.subscribers(event.event_type) {
+            tokio::spawn(deliver(registry.clone(), webhook, event.clone()));
+        }
+    }
+}